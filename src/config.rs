@@ -1,7 +1,13 @@
 use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 
+use crate::cmdgen::Transport;
+use crate::instance_details::InstanceDetails;
+use crate::rules::Rule;
+use crate::search::MatchMode;
+
 pub const CONFIG_PATH: &str = "~/.config/blaze/config.toml";
 #[allow(dead_code)]
 pub const DEFAULT: &str = r#"
@@ -13,7 +19,7 @@ pub const DEFAULT: &str = r#"
     address-type = "private"
 "#;
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
 pub struct Config {
     #[serde(rename = "default-user")]
     pub default_user: Option<String>,
@@ -23,11 +29,137 @@ pub struct Config {
     pub port: Option<u16>,
     #[serde(rename = "address-type")]
     pub address_type: Option<String>,
+    #[serde(rename = "page-size")]
+    pub page_size: Option<i32>,
+    #[serde(rename = "max-results")]
+    pub max_results: Option<usize>,
+    pub regions: Option<Vec<String>>,
+    pub profiles: Option<Vec<String>>,
+    #[serde(rename = "default-filters")]
+    pub default_filters: Option<BTreeMap<String, FilterValues>>,
+    #[serde(rename = "match-mode")]
+    pub match_mode: Option<MatchMode>,
+    /// seconds before a cached instance list is refetched
+    #[serde(rename = "cache-ttl")]
+    pub cache_ttl: Option<u64>,
+    #[serde(rename = "stale-while-revalidate")]
+    pub stale_while_revalidate: Option<bool>,
+    /// instance tag holding a per-instance ssh port, "ssh-port" by default
+    #[serde(rename = "port-tag")]
+    pub port_tag: Option<String>,
+    /// how instances are connected to, "ssh" by default
+    pub transport: Option<Transport>,
+    /// aws cli executable used by the ssm transports, "aws" from PATH by default
+    #[serde(rename = "aws-binary")]
+    pub aws_binary: Option<String>,
+    /// push a short lived key with ec2 instance connect before every connection
+    #[serde(rename = "instance-connect")]
+    pub instance_connect: Option<bool>,
+    /// ec2 instance connect endpoint to use instead of the regional one
+    #[serde(rename = "instance-connect-endpoint")]
+    pub instance_connect_endpoint: Option<String>,
+    /// ec2 instance connect endpoint to tunnel through with the eice transport, picked from the
+    /// instance's vpc & subnet by default
+    #[serde(rename = "eice-id")]
+    pub eice_id: Option<String>,
+    /// name of the jump chain to connect through, see `jump-chains`
+    #[serde(rename = "jump-chain")]
+    pub jump_chain: Option<String>,
+    /// passed to ssh as `-o <option>` each
+    #[serde(rename = "ssh-options")]
+    pub ssh_options: Option<Vec<String>>,
+    /// ssh executable to run, "ssh" from PATH by default
+    #[serde(rename = "ssh-binary")]
+    pub ssh_binary: Option<String>,
+    #[serde(skip)]
+    pub accounts: Vec<Account>,
+    #[serde(skip)]
+    pub jump_chains: Vec<JumpChain>,
+    #[serde(skip)]
+    pub rules: Vec<Rule>,
+}
+
+/// values of a server side ec2 filter, either a single value or a list
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum FilterValues {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl FilterValues {
+    pub fn values(&self) -> Vec<String> {
+        match self {
+            FilterValues::One(value) => vec![value.clone()],
+            FilterValues::Many(values) => values.clone(),
+        }
+    }
+}
+
+/// an aws account reached by assuming a role, selectable by name like a profile
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Account {
+    pub name: String,
+    #[serde(rename = "role-arn")]
+    pub role_arn: String,
+    #[serde(rename = "external-id")]
+    pub external_id: Option<String>,
+    #[serde(rename = "mfa-serial")]
+    pub mfa_serial: Option<String>,
+    #[serde(rename = "session-name")]
+    pub session_name: Option<String>,
+    /// aws profile providing the credentials the role is assumed with
+    #[serde(rename = "source-profile")]
+    pub source_profile: Option<String>,
+    /// another account whose role is assumed first (role chaining)
+    #[serde(rename = "source-account")]
+    pub source_account: Option<String>,
+}
+
+/// jump hosts connected through in order, each with its own user, port & key
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct JumpChain {
+    pub name: String,
+    pub hops: Vec<JumpHop>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+/* a misspelled hop setting would otherwise fall back to the target's */
+#[serde(deny_unknown_fields)]
+pub struct JumpHop {
+    pub host: Option<String>,
+    /// resolves the hop to a running instance with this tag (`Key=Value` or `Key`) in the
+    /// target's vpc, instead of a fixed host
+    #[serde(rename = "bastion-tag")]
+    pub bastion_tag: Option<String>,
+    pub user: Option<String>,
+    pub port: Option<u16>,
+    pub key: Option<PathBuf>,
+    /// address of a bastion to connect to, its public address when it has one by default
+    #[serde(rename = "address-type")]
+    pub address_type: Option<String>,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
 struct ConfigFile {
     config: Config,
+    #[serde(default)]
+    accounts: Vec<Account>,
+    #[serde(default, rename = "jump-chains")]
+    jump_chains: Vec<JumpChain>,
+    #[serde(default)]
+    rules: Vec<Rule>,
+}
+
+impl ConfigFile {
+    fn into_config(self) -> Config {
+        Config {
+            accounts: self.accounts,
+            jump_chains: self.jump_chains,
+            rules: self.rules,
+            ..self.config
+        }
+    }
 }
 
 impl Config {
@@ -65,13 +197,39 @@ impl Config {
         std::fs::read_to_string(config_path).map_err(|e| e.into())
     }
 
+    pub fn account(&self, name: &str) -> Option<&Account> {
+        self.accounts.iter().find(|account| account.name == name)
+    }
+
+    pub fn jump_chain(&self, name: &str) -> Option<&JumpChain> {
+        self.jump_chains.iter().find(|chain| chain.name == name)
+    }
+
+    /// the first rule matching the instance & its position
+    pub fn rule(&self, instance: &InstanceDetails) -> Option<(usize, &Rule)> {
+        self.rules
+            .iter()
+            .enumerate()
+            .find(|(_, rule)| rule.matches(instance))
+    }
+
     pub fn load(path: Option<PathBuf>) -> Result<Self> {
         let config_path =
             PathBuf::from(shellexpand::tilde(&Self::get_config_path(path)?).to_string());
         let raw_config = std::fs::read_to_string(config_path);
         match raw_config {
-            Ok(config) => Ok(toml::from_str::<ConfigFile>(&config)?.config),
+            Ok(config) => Ok(toml::from_str::<ConfigFile>(&config)?.into_config()),
             Err(_e) => Err(anyhow!("Config not found at {}, Please use `blssh configure` to generate default configuration.", CONFIG_PATH)),
         }
     }
+
+    /// like `load`, but falls back to an empty config when no config file exists
+    pub fn load_or_default(path: Option<PathBuf>) -> Result<Self> {
+        let config_path =
+            PathBuf::from(shellexpand::tilde(&Self::get_config_path(path)?).to_string());
+        match std::fs::read_to_string(config_path) {
+            Ok(config) => Ok(toml::from_str::<ConfigFile>(&config)?.into_config()),
+            Err(_e) => Ok(Self::default()),
+        }
+    }
 }
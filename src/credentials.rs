@@ -0,0 +1,312 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+
+use anyhow::{anyhow, Result};
+use aws_config::{Region, SdkConfig};
+use aws_sdk_ec2::config::{Credentials, SharedCredentialsProvider};
+use serde::{Deserialize, Serialize};
+
+use crate::cache;
+use crate::clock::now;
+use crate::cmdgen::CommandGenerator;
+use crate::config::{Account, Config};
+
+pub const DEFAULT_SESSION_NAME: &str = "blaze-ssh";
+/* refresh cached credentials this long before they actually expire */
+const EXPIRY_MARGIN_SECS: i64 = 300;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RoleCredentials {
+    pub role_arn: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: String,
+    pub expiration: i64,
+}
+
+impl RoleCredentials {
+    pub fn is_expired(&self) -> bool {
+        self.expiration - EXPIRY_MARGIN_SECS <= now()
+    }
+
+    /// the environment variables handing these credentials to the aws cli
+    pub fn env(&self) -> Vec<(String, String)> {
+        vec![
+            (
+                String::from("AWS_ACCESS_KEY_ID"),
+                self.access_key_id.clone(),
+            ),
+            (
+                String::from("AWS_SECRET_ACCESS_KEY"),
+                self.secret_access_key.clone(),
+            ),
+            (
+                String::from("AWS_SESSION_TOKEN"),
+                self.session_token.clone(),
+            ),
+        ]
+    }
+
+    fn provider(&self) -> SharedCredentialsProvider {
+        SharedCredentialsProvider::new(Credentials::new(
+            self.access_key_id.clone(),
+            self.secret_access_key.clone(),
+            Some(self.session_token.clone()),
+            Some(UNIX_EPOCH + std::time::Duration::from_secs(self.expiration.max(0) as u64)),
+            "blaze-ssh-assume-role",
+        ))
+    }
+
+    fn cache_path(account: &str) -> Result<PathBuf> {
+        Ok(cache::cache_dir()?
+            .join("credentials")
+            .join(format!("{}.json", cache::file_name(account))))
+    }
+
+    fn read_cache(account: &Account) -> Option<Self> {
+        let credentials = cache::read::<Self>(&Self::cache_path(&account.name).ok()?).ok()??;
+
+        match credentials.role_arn == account.role_arn && !credentials.is_expired() {
+            true => Some(credentials),
+            false => None,
+        }
+    }
+
+    /* credentials are secrets, cache entries are only readable by the current user */
+    fn write(&self, account: &Account) -> Result<()> {
+        cache::write(&Self::cache_path(&account.name)?, self)
+    }
+}
+
+/// builds an sdk config for the named account by assuming its role, following
+/// `source-account` links to assume every role in the chain in order. MFA codes are only
+/// prompted for when `interactive`, otherwise a role needing one fails to be assumed
+pub async fn assume_role_config(
+    name: &str,
+    config: &Config,
+    interactive: bool,
+) -> Result<SdkConfig> {
+    Ok(assume_role_chain(name, config, interactive).await?.0)
+}
+
+/// hands the role credentials of instances found through a configured account to the command
+/// generators running the aws cli, which knows nothing about accounts. every role is assumed
+/// once, & a role that can't be assumed only fails the instances of its account
+pub async fn resolve<'a>(
+    command_generators: impl IntoIterator<Item = &'a mut CommandGenerator>,
+    config: &Config,
+) {
+    let mut resolved = HashMap::new();
+    for command_generator in command_generators {
+        let Some(account) = command_generator.role_account() else {
+            continue;
+        };
+        if !resolved.contains_key(&account) {
+            let credentials = assume_role_chain(&account, config, true)
+                .await
+                .map(|(_, credentials)| credentials)
+                .map_err(|e| e.to_string());
+            resolved.insert(account.clone(), credentials);
+        }
+
+        command_generator.set_role_credentials(resolved[&account].clone());
+    }
+}
+
+/* the sdk config & credentials of the last role in the chain */
+async fn assume_role_chain(
+    name: &str,
+    config: &Config,
+    interactive: bool,
+) -> Result<(SdkConfig, RoleCredentials)> {
+    let chain = role_chain(name, config)?;
+    let root = chain.first().ok_or(anyhow!("Unknown account {}", name))?;
+
+    let mut sdk_config = match root.source_profile.clone() {
+        Some(profile) => aws_config::from_env().profile_name(profile).load().await,
+        None => aws_config::load_from_env().await,
+    };
+
+    /* sts needs a region to sign requests with, even though assumed roles are global */
+    if sdk_config.region().is_none() {
+        sdk_config = sdk_config
+            .to_builder()
+            .region(Region::new("us-east-1"))
+            .build();
+    }
+
+    let mut assumed = None;
+    for account in chain.iter() {
+        let credentials = match RoleCredentials::read_cache(account) {
+            Some(credentials) => credentials,
+            None => {
+                let credentials = assume_role(&sdk_config, account, interactive).await?;
+                credentials.write(account)?;
+                credentials
+            }
+        };
+
+        sdk_config = sdk_config
+            .to_builder()
+            .credentials_provider(credentials.provider())
+            .build();
+        assumed = Some(credentials);
+    }
+
+    let credentials = assumed.ok_or(anyhow!("Unknown account {}", name))?;
+    Ok((sdk_config, credentials))
+}
+
+/// the profile holding the base credentials of the named account's assume-role chain
+pub fn source_profile(name: &str, config: &Config) -> Option<String> {
+    role_chain(name, config)
+        .ok()?
+        .first()?
+        .source_profile
+        .clone()
+}
+
+/* accounts ordered from the one assumed first to the requested one */
+fn role_chain<'a>(name: &str, config: &'a Config) -> Result<Vec<&'a Account>> {
+    let mut chain = Vec::new();
+    let mut next = Some(name.to_string());
+    while let Some(name) = next {
+        let account = config
+            .account(&name)
+            .ok_or(anyhow!("Unknown account {} in assume-role chain", name))?;
+        if chain.contains(&account) {
+            return Err(anyhow!("Assume-role chain for {} contains a cycle", name));
+        }
+
+        chain.push(account);
+        next = account.source_account.clone();
+    }
+
+    chain.reverse();
+    Ok(chain)
+}
+
+async fn assume_role(
+    sdk_config: &SdkConfig,
+    account: &Account,
+    interactive: bool,
+) -> Result<RoleCredentials> {
+    /* stdin belongs to the picker or /dev/null when refreshing in the background */
+    if account.mfa_serial.is_some() && !interactive {
+        return Err(anyhow!(
+            "Assuming the role of {} needs an MFA code, run blssh interactively to enter one",
+            account.name
+        ));
+    }
+
+    let client = aws_sdk_sts::Client::new(sdk_config);
+    let mut request = client
+        .assume_role()
+        .role_arn(account.role_arn.clone())
+        .role_session_name(
+            account
+                .session_name
+                .clone()
+                .unwrap_or(DEFAULT_SESSION_NAME.to_string()),
+        )
+        .set_external_id(account.external_id.clone());
+
+    if let Some(mfa_serial) = account.mfa_serial.clone() {
+        request = request
+            .serial_number(mfa_serial.clone())
+            .token_code(prompt_mfa_token(&mfa_serial)?);
+    }
+
+    let response = request.send().await?;
+    let credentials = response
+        .credentials()
+        .ok_or(anyhow!("No credentials returned for {}", account.role_arn))?;
+
+    Ok(RoleCredentials {
+        role_arn: account.role_arn.clone(),
+        access_key_id: credentials.access_key_id().to_string(),
+        secret_access_key: credentials.secret_access_key().to_string(),
+        session_token: credentials.session_token().to_string(),
+        expiration: credentials.expiration().secs(),
+    })
+}
+
+fn prompt_mfa_token(mfa_serial: &str) -> Result<String> {
+    eprint!("MFA code for {}: ", mfa_serial);
+    std::io::stderr().flush()?;
+
+    let mut token = String::new();
+    std::io::stdin().read_line(&mut token)?;
+
+    Ok(token.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account(name: &str, source_account: Option<&str>) -> Account {
+        Account {
+            name: String::from(name),
+            role_arn: format!("arn:aws:iam::123456789012:role/{}", name),
+            source_account: source_account.map(String::from),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn role_chain_orders_source_accounts_first() {
+        let config = Config {
+            accounts: vec![
+                account("prod", Some("hub")),
+                account("hub", Some("identity")),
+                account("identity", None),
+            ],
+            ..Default::default()
+        };
+
+        let chain = role_chain("prod", &config)
+            .unwrap()
+            .into_iter()
+            .map(|account| account.name.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(chain, vec!["identity", "hub", "prod"]);
+    }
+
+    #[test]
+    fn role_chain_rejects_cycles() {
+        let config = Config {
+            accounts: vec![account("a", Some("b")), account("b", Some("a"))],
+            ..Default::default()
+        };
+
+        assert!(role_chain("a", &config).is_err());
+    }
+
+    #[tokio::test]
+    async fn mfa_roles_are_not_assumed_non_interactively() {
+        let account = Account {
+            mfa_serial: Some(String::from("arn:aws:iam::123456789012:mfa/me")),
+            ..account("prod", None)
+        };
+        let sdk_config = SdkConfig::builder().build();
+
+        let error = assume_role(&sdk_config, &account, false).await.unwrap_err();
+        assert!(error.to_string().contains("MFA code"));
+    }
+
+    #[test]
+    fn credentials_expire_within_margin() {
+        let credentials = RoleCredentials {
+            role_arn: String::from("arn"),
+            access_key_id: String::from("key"),
+            secret_access_key: String::from("secret"),
+            session_token: String::from("token"),
+            expiration: now() + 60,
+        };
+
+        assert!(credentials.is_expired());
+    }
+}
@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use aws_config::Region;
+use aws_sdk_ec2::types::{Ec2InstanceConnectEndpointState, Filter};
+
+use crate::cmdgen::CommandGenerator;
+use crate::config::Config;
+use crate::instance_details::{InstanceDetails, InstanceSet};
+
+/// an ec2 instance connect endpoint, reachable by every instance of its vpc
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoint {
+    pub id: String,
+    pub vpc_id: Option<String>,
+    pub subnet_id: Option<String>,
+}
+
+/// the endpoint in the instance's subnet, or else any endpoint in its vpc
+pub fn choose<'a>(endpoints: &'a [Endpoint], instance: &InstanceDetails) -> Option<&'a Endpoint> {
+    let in_vpc = endpoints
+        .iter()
+        .filter(|endpoint| endpoint.vpc_id.is_some() && endpoint.vpc_id == instance.vpc_id)
+        .collect::<Vec<_>>();
+
+    in_vpc
+        .iter()
+        .find(|endpoint| endpoint.subnet_id.is_some() && endpoint.subnet_id == instance.subnet_id)
+        .or(in_vpc.first())
+        .copied()
+}
+
+/// picks the endpoint of every command generator tunnelling through one without a configured
+/// endpoint id. endpoints are looked up once per account, region & vpc. instances without an
+/// endpoint, or whose lookup failed, are left without one & generating their command fails
+pub async fn resolve<'a>(
+    command_generators: impl IntoIterator<Item = &'a mut CommandGenerator>,
+    config: &Config,
+) {
+    resolve_with(command_generators, async |(profile, region, vpc_id)| {
+        fetch(profile, region, vpc_id, config).await
+    })
+    .await
+}
+
+/* (profile, region, vpc id) */
+type VpcKey = (Option<String>, Option<String>, String);
+
+/* a lookup failing in one account or region only fails the instances it was done for */
+async fn resolve_with<'a>(
+    command_generators: impl IntoIterator<Item = &'a mut CommandGenerator>,
+    mut fetch: impl AsyncFnMut(&VpcKey) -> Result<Vec<Endpoint>>,
+) {
+    let mut endpoints = HashMap::new();
+    for command_generator in command_generators {
+        if !command_generator.needs_instance_connect_endpoint() {
+            continue;
+        }
+
+        let instance = command_generator.instance();
+        let Some(vpc_id) = instance.vpc_id.clone() else {
+            continue;
+        };
+        let key = (instance.profile.clone(), instance.region.clone(), vpc_id);
+        if !endpoints.contains_key(&key) {
+            let fetched = fetch(&key).await.map_err(|e| format!("{:#}", e));
+            endpoints.insert(key.clone(), fetched);
+        }
+
+        let endpoint =
+            match &endpoints[&key] {
+                Ok(endpoints) => Ok(choose(endpoints, command_generator.instance())
+                    .map(|endpoint| endpoint.id.clone())),
+                Err(e) => Err(e.clone()),
+            };
+        command_generator.set_instance_connect_endpoint(endpoint);
+    }
+}
+
+/* the usable (create-complete) endpoints of a vpc */
+async fn fetch(
+    profile: &Option<String>,
+    region: &Option<String>,
+    vpc_id: &str,
+    config: &Config,
+) -> Result<Vec<Endpoint>> {
+    let mut aws_config = InstanceSet::load_aws_config(profile, config, true).await?;
+    if let Some(region) = region {
+        aws_config = aws_config
+            .to_builder()
+            .region(Region::new(region.clone()))
+            .build();
+    }
+    let client = aws_sdk_ec2::Client::new(&aws_config);
+
+    let mut pages = client
+        .describe_instance_connect_endpoints()
+        .filters(Filter::builder().name("vpc-id").values(vpc_id).build())
+        .into_paginator()
+        .send();
+
+    let mut endpoints = Vec::new();
+    while let Some(page) = pages.next().await {
+        endpoints.extend(
+            page?
+                .instance_connect_endpoints()
+                .iter()
+                .filter(|endpoint| {
+                    endpoint.state() == Some(&Ec2InstanceConnectEndpointState::CreateComplete)
+                })
+                .filter_map(|endpoint| {
+                    Some(Endpoint {
+                        id: endpoint.instance_connect_endpoint_id()?.to_string(),
+                        vpc_id: endpoint.vpc_id().map(String::from),
+                        subnet_id: endpoint.subnet_id().map(String::from),
+                    })
+                }),
+        );
+    }
+
+    Ok(endpoints)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmdgen::Transport;
+    use crate::opts::ConnectOptions;
+    use anyhow::anyhow;
+
+    fn endpoint(id: &str, vpc_id: &str, subnet_id: &str) -> Endpoint {
+        Endpoint {
+            id: id.to_string(),
+            vpc_id: Some(vpc_id.to_string()),
+            subnet_id: Some(subnet_id.to_string()),
+        }
+    }
+
+    fn instance(vpc_id: &str, subnet_id: &str) -> InstanceDetails {
+        InstanceDetails {
+            vpc_id: Some(vpc_id.to_string()),
+            subnet_id: Some(subnet_id.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_lookups_only_fail_their_own_instances() {
+        let config = Config {
+            default_user: Some(String::from("ec2-user")),
+            transport: Some(Transport::Eice),
+            ..Default::default()
+        };
+        let generator = |id: &str, profile: &str, subnet_id: &str| {
+            let instance = InstanceDetails {
+                instance_id: Some(id.to_string()),
+                profile: Some(profile.to_string()),
+                ..instance("vpc-1", subnet_id)
+            };
+            CommandGenerator::new(&ConnectOptions::default(), config.clone(), instance).unwrap()
+        };
+        let mut generators = [
+            generator("i-1", "staging", "subnet-a"),
+            generator("i-2", "production", "subnet-a"),
+            generator("i-3", "staging", "subnet-b"),
+            generator("i-4", "staging", "subnet-c"),
+        ];
+
+        let mut lookups = Vec::new();
+        resolve_with(generators.iter_mut(), async |key: &VpcKey| {
+            lookups.push(key.clone());
+            match key.0.as_deref() {
+                Some("production") => Err(anyhow!("UnauthorizedOperation")),
+                _ => Ok(vec![
+                    endpoint("eice-a", "vpc-1", "subnet-a"),
+                    endpoint("eice-b", "vpc-1", "subnet-b"),
+                ]),
+            }
+        })
+        .await;
+
+        assert_eq!(lookups.len(), 2);
+        let proxy_command = |generator: &CommandGenerator| {
+            generator
+                .args()
+                .map(|args| args[3].clone())
+                .map_err(|e| e.to_string())
+        };
+        assert!(proxy_command(&generators[0]).unwrap().contains("eice-a"));
+        assert!(proxy_command(&generators[1])
+            .unwrap_err()
+            .contains("UnauthorizedOperation"));
+        assert!(proxy_command(&generators[2]).unwrap().contains("eice-b"));
+        assert!(proxy_command(&generators[3]).unwrap().contains("eice-a"));
+    }
+
+    #[test]
+    fn prefers_the_endpoint_in_the_instance_subnet() {
+        let endpoints = vec![
+            endpoint("eice-other", "vpc-2", "subnet-b"),
+            endpoint("eice-vpc", "vpc-1", "subnet-a"),
+            endpoint("eice-subnet", "vpc-1", "subnet-b"),
+        ];
+
+        assert_eq!(
+            choose(&endpoints, &instance("vpc-1", "subnet-b")).map(|e| e.id.as_str()),
+            Some("eice-subnet")
+        );
+        assert_eq!(
+            choose(&endpoints, &instance("vpc-1", "subnet-c")).map(|e| e.id.as_str()),
+            Some("eice-vpc")
+        );
+        assert_eq!(choose(&endpoints, &instance("vpc-3", "subnet-b")), None);
+    }
+}
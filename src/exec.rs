@@ -0,0 +1,479 @@
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::sync::Semaphore;
+use tokio::task::{JoinHandle, JoinSet};
+
+use crate::cmdgen::{failure_reason, CommandGenerator};
+use crate::config::Config;
+use crate::instance_details::InstanceDetails;
+use crate::opts::ExecOptions;
+
+pub const DEFAULT_CONCURRENCY: usize = 10;
+const OUTPUT_GRACE_PERIOD: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExecStatus {
+    Succeeded,
+    Failed,
+    TimedOut,
+    /// ssh couldn't be started
+    Error,
+    /// never ran, or was killed by --fail-fast
+    Cancelled,
+}
+
+impl ExecStatus {
+    pub fn label(&self) -> &'static str {
+        match self {
+            ExecStatus::Succeeded => "ok",
+            ExecStatus::Failed => "failed",
+            ExecStatus::TimedOut => "timed out",
+            ExecStatus::Error => "error",
+            ExecStatus::Cancelled => "cancelled",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ExecResult {
+    pub name: String,
+    pub instance_id: Option<String>,
+    pub status: ExecStatus,
+    pub exit_code: Option<i32>,
+    pub duration_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// only captured for --json, otherwise output is streamed as it arrives
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stdout: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stderr: Vec<String>,
+}
+
+impl ExecResult {
+    fn new(name: &str, instance_id: Option<String>, status: ExecStatus) -> Self {
+        Self {
+            name: name.to_string(),
+            instance_id,
+            status,
+            exit_code: None,
+            duration_ms: 0,
+            error: None,
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+        }
+    }
+}
+
+/* a command to run, labelled with the instance it runs on */
+struct Job {
+    name: String,
+    instance_id: Option<String>,
+    /* has to succeed right before the command runs, see `CommandGenerator::prepare_command` */
+    prepare: Option<Command>,
+    command: Result<Command>,
+}
+
+pub struct Executor {
+    opts: ExecOptions,
+    config: Config,
+}
+
+impl Executor {
+    pub fn new(opts: &ExecOptions, config: Config) -> Result<Self> {
+        if opts.connect.command.is_empty() {
+            return Err(anyhow!(
+                "No command given. Please pass the command to run after --"
+            ));
+        }
+
+        if opts.connect.tmux.is_some() {
+            return Err(anyhow!("--tmux can't be used with exec"));
+        }
+
+        Ok(Self {
+            opts: opts.clone(),
+            config,
+        })
+    }
+
+    /// runs the command on every instance, returning a result per instance in the same order.
+    /// bastions of jump chains are looked up in the `inventory`
+    pub async fn run(
+        &self,
+        instances: Vec<InstanceDetails>,
+        inventory: &[InstanceDetails],
+    ) -> Result<Vec<ExecResult>> {
+        /* parallel sessions can't share a terminal, so a tty is only allocated with -t */
+        let connect_opts = crate::opts::ConnectOptions {
+            no_tty: !self.opts.connect.tty,
+            ..self.opts.connect.clone()
+        };
+
+        let mut command_generators = instances
+            .into_iter()
+            .map(|instance| {
+                let name = instance
+                    .instance_name
+                    .clone()
+                    .or(instance.instance_id.clone())
+                    .unwrap_or("None".to_string());
+                let instance_id = instance.instance_id.clone();
+                let command_generator =
+                    CommandGenerator::new(&connect_opts, self.config.clone(), instance).map(
+                        |mut command_generator| {
+                            command_generator.resolve_bastions(inventory);
+                            command_generator
+                        },
+                    );
+                (name, instance_id, command_generator)
+            })
+            .collect::<Vec<_>>();
+        crate::eice::resolve(
+            command_generators
+                .iter_mut()
+                .filter_map(|(_, _, command_generator)| command_generator.as_mut().ok()),
+            &self.config,
+        )
+        .await;
+        crate::jump::resolve(
+            command_generators
+                .iter_mut()
+                .filter_map(|(_, _, command_generator)| command_generator.as_mut().ok()),
+            &self.config,
+        )
+        .await;
+        crate::credentials::resolve(
+            command_generators
+                .iter_mut()
+                .filter_map(|(_, _, command_generator)| command_generator.as_mut().ok()),
+            &self.config,
+        )
+        .await;
+
+        let jobs = command_generators
+            .into_iter()
+            .map(|(name, instance_id, command_generator)| {
+                let commands = command_generator.and_then(|command_generator| {
+                    Ok((
+                        command_generator.prepare_command()?,
+                        command_generator.command()?,
+                    ))
+                });
+
+                match commands {
+                    Ok((prepare, command)) => Job {
+                        name,
+                        instance_id,
+                        prepare,
+                        command: Ok(command),
+                    },
+                    Err(e) => Job {
+                        name,
+                        instance_id,
+                        prepare: None,
+                        command: Err(e),
+                    },
+                }
+            })
+            .collect::<Vec<_>>();
+
+        self.run_jobs(jobs).await
+    }
+
+    async fn run_jobs(&self, jobs: Vec<Job>) -> Result<Vec<ExecResult>> {
+        let width = jobs.iter().map(|job| job.name.chars().count()).max();
+        let timeout = self.opts.timeout.map(Duration::from_secs);
+        let stream = !self.opts.json;
+        let semaphore = Arc::new(Semaphore::new(self.opts.concurrency.max(1)));
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let fail_fast = self.opts.fail_fast;
+
+        let mut results = jobs
+            .iter()
+            .map(|job| ExecResult::new(&job.name, job.instance_id.clone(), ExecStatus::Cancelled))
+            .collect::<Vec<_>>();
+
+        let mut tasks = JoinSet::new();
+        for (index, job) in jobs.into_iter().enumerate() {
+            let (semaphore, cancelled) = (semaphore.clone(), cancelled.clone());
+            let prefix = format!("{:<width$} | ", job.name, width = width.unwrap_or_default());
+            tasks.spawn(async move {
+                let _permit = semaphore.acquire_owned().await;
+                if cancelled.load(Ordering::SeqCst) {
+                    let result = ExecResult::new(&job.name, job.instance_id, ExecStatus::Cancelled);
+                    return (index, result);
+                }
+
+                /* flagged while still holding the permit, so no queued job starts after it */
+                let result = run_job(job, prefix, timeout, stream).await;
+                if fail_fast && result.status != ExecStatus::Succeeded {
+                    cancelled.store(true, Ordering::SeqCst);
+                }
+                (index, result)
+            });
+        }
+
+        while let Some(joined) = tasks.join_next().await {
+            let (index, result) = joined?;
+            let failed = result.status != ExecStatus::Succeeded;
+            results[index] = result;
+
+            /* dropping a running job kills its ssh process */
+            if failed && fail_fast {
+                tasks.abort_all();
+                while let Some(joined) = tasks.join_next().await {
+                    if let Ok((index, result)) = joined {
+                        results[index] = result;
+                    }
+                }
+            }
+        }
+
+        Ok(results)
+    }
+}
+
+/* the timeout covers the key push & the command together */
+async fn run_job(job: Job, prefix: String, timeout: Option<Duration>, stream: bool) -> ExecResult {
+    let started = Instant::now();
+    let deadline = timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+    let mut result = ExecResult::new(&job.name, job.instance_id, ExecStatus::Error);
+
+    if let Some(prepare) = job.prepare {
+        let output = tokio::process::Command::from(prepare)
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .output();
+        let output = match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, output).await,
+            None => Ok(output.await),
+        };
+
+        match output {
+            Ok(Ok(output)) if output.status.success() => {}
+            Ok(Ok(output)) => {
+                result.error = Some(format!(
+                    "Unable to send the ssh public key: {}",
+                    failure_reason(&output)
+                ));
+                return result;
+            }
+            Ok(Err(e)) => {
+                result.error = Some(e.to_string());
+                return result;
+            }
+            Err(_) => {
+                result.status = ExecStatus::TimedOut;
+                result.error = Some(String::from("Timed out sending the ssh public key"));
+                result.duration_ms = started.elapsed().as_millis() as u64;
+                return result;
+            }
+        }
+    }
+
+    let mut child = match job.command.and_then(|command| {
+        Ok(tokio::process::Command::from(command)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?)
+    }) {
+        Ok(child) => child,
+        Err(e) => {
+            result.error = Some(e.to_string());
+            return result;
+        }
+    };
+
+    let stdout = tokio::spawn(forward_lines(
+        child.stdout.take(),
+        prefix.clone(),
+        stream,
+        false,
+    ));
+    let stderr = tokio::spawn(forward_lines(child.stderr.take(), prefix, stream, true));
+
+    let status = match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, child.wait()).await,
+        None => Ok(child.wait().await),
+    };
+
+    let mut grace = None;
+    match status {
+        Ok(Ok(status)) => {
+            result.exit_code = status.code();
+            result.status = match status.success() {
+                true => ExecStatus::Succeeded,
+                false => ExecStatus::Failed,
+            };
+        }
+        Ok(Err(e)) => result.error = Some(e.to_string()),
+        Err(_) => {
+            let _ = child.kill().await;
+            result.status = ExecStatus::TimedOut;
+            grace = Some(OUTPUT_GRACE_PERIOD);
+        }
+    }
+
+    result.stdout = collect_lines(stdout, grace).await;
+    result.stderr = collect_lines(stderr, grace).await;
+    result.duration_ms = started.elapsed().as_millis() as u64;
+    result
+}
+
+/* processes started by a killed ssh (e.g. a ProxyCommand) may hold its output open, so
+ * readers only get a grace period after a timeout */
+async fn collect_lines(
+    mut handle: JoinHandle<Vec<String>>,
+    grace: Option<Duration>,
+) -> Vec<String> {
+    match grace {
+        Some(grace) => match tokio::time::timeout(grace, &mut handle).await {
+            Ok(lines) => lines.unwrap_or_default(),
+            Err(_) => {
+                handle.abort();
+                Vec::new()
+            }
+        },
+        None => handle.await.unwrap_or_default(),
+    }
+}
+
+/* prints every line prefixed with the instance name, or collects them when not streaming */
+async fn forward_lines<R: AsyncRead + Unpin>(
+    reader: Option<R>,
+    prefix: String,
+    stream: bool,
+    stderr: bool,
+) -> Vec<String> {
+    let mut collected = Vec::new();
+    let Some(reader) = reader else {
+        return collected;
+    };
+
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        match (stream, stderr) {
+            (true, false) => println!("{}{}", prefix, line),
+            (true, true) => eprintln!("{}{}", prefix, line),
+            (false, _) => collected.push(line),
+        }
+    }
+
+    collected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn executor(concurrency: usize, timeout: Option<u64>, fail_fast: bool) -> Executor {
+        let opts = ExecOptions {
+            connect: crate::opts::ConnectOptions {
+                command: vec![String::from("true")],
+                ..Default::default()
+            },
+            concurrency,
+            timeout,
+            pick: false,
+            fail_fast,
+            json: true,
+        };
+
+        Executor::new(&opts, Config::default()).unwrap()
+    }
+
+    fn job(name: &str, script: &str) -> Job {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(script);
+
+        Job {
+            name: name.to_string(),
+            instance_id: Some(format!("i-{}", name)),
+            prepare: None,
+            command: Ok(command),
+        }
+    }
+
+    #[tokio::test]
+    async fn collects_output_and_exit_codes_in_instance_order() {
+        let results = executor(2, None, false)
+            .run_jobs(vec![
+                job("web-1", "sleep 0.2; echo up"),
+                job("web-2", "echo oops >&2; exit 3"),
+            ])
+            .await
+            .unwrap();
+
+        assert_eq!(results[0].status, ExecStatus::Succeeded);
+        assert_eq!(results[0].stdout, vec!["up"]);
+        assert_eq!(results[1].status, ExecStatus::Failed);
+        assert_eq!(results[1].exit_code, Some(3));
+        assert_eq!(results[1].stderr, vec!["oops"]);
+    }
+
+    #[tokio::test]
+    async fn kills_commands_exceeding_the_timeout() {
+        let results = executor(1, Some(1), false)
+            .run_jobs(vec![job("web-1", "sleep 10")])
+            .await
+            .unwrap();
+
+        assert_eq!(results[0].status, ExecStatus::TimedOut);
+        assert!(results[0].duration_ms < 5000);
+    }
+
+    #[tokio::test]
+    async fn key_pushes_share_the_timeout() {
+        let mut prepare = Command::new("sh");
+        prepare.arg("-c").arg("sleep 10");
+        let job = Job {
+            prepare: Some(prepare),
+            ..job("web-1", "echo ran")
+        };
+
+        let results = executor(1, Some(1), false)
+            .run_jobs(vec![job])
+            .await
+            .unwrap();
+
+        assert_eq!(results[0].status, ExecStatus::TimedOut);
+        assert!(results[0].stdout.is_empty());
+        assert!(results[0].duration_ms < 5000);
+    }
+
+    #[tokio::test]
+    async fn fail_fast_cancels_the_remaining_instances() {
+        let results = executor(1, None, true)
+            .run_jobs(vec![job("web-1", "exit 1"), job("web-2", "echo ran")])
+            .await
+            .unwrap();
+
+        assert_eq!(results[0].status, ExecStatus::Failed);
+        assert_eq!(results[1].status, ExecStatus::Cancelled);
+        assert!(results[1].stdout.is_empty());
+    }
+
+    #[test]
+    fn requires_a_command() {
+        let opts = ExecOptions {
+            connect: Default::default(),
+            concurrency: 1,
+            timeout: None,
+            pick: false,
+            fail_fast: false,
+            json: false,
+        };
+
+        assert!(Executor::new(&opts, Config::default()).is_err());
+    }
+}
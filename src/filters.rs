@@ -0,0 +1,151 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use anyhow::{anyhow, Result};
+use aws_sdk_ec2::types::Filter;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+
+/// server side filters passed to DescribeInstances, keyed by filter name
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct Ec2Filters(BTreeMap<String, Vec<String>>);
+
+impl Ec2Filters {
+    /// merges the `default-filters` config table with `--filter` args. a filter given on the
+    /// command line replaces the default filter of the same name
+    pub fn new(args: &[String], config: &Config) -> Result<Self> {
+        let mut filters = config
+            .default_filters
+            .clone()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(name, values)| (name, values.values()))
+            .collect::<BTreeMap<_, _>>();
+
+        for arg in args {
+            let (name, values) = Self::parse(arg)?;
+            filters.insert(name, values);
+        }
+
+        Ok(Self(filters))
+    }
+
+    /* "name=value1,value2" */
+    fn parse(arg: &str) -> Result<(String, Vec<String>)> {
+        let (name, values) = arg.split_once('=').ok_or(anyhow!(
+            "Invalid filter \"{}\", expected Name=Value[,Value...]",
+            arg
+        ))?;
+
+        let name = name.trim();
+        let values = values
+            .split(',')
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+            .collect::<Vec<_>>();
+
+        match name.is_empty() || values.is_empty() {
+            true => Err(anyhow!(
+                "Invalid filter \"{}\", expected Name=Value[,Value...]",
+                arg
+            )),
+            false => Ok((name.to_string(), values)),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn to_sdk(&self) -> Vec<Filter> {
+        self.0
+            .iter()
+            .map(|(name, values)| {
+                Filter::builder()
+                    .name(name)
+                    .set_values(Some(values.clone()))
+                    .build()
+            })
+            .collect()
+    }
+}
+
+/* the command line form, e.g. "instance-state-name=running tag:Env=prod,staging" */
+impl fmt::Display for Ec2Filters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let filters = self
+            .0
+            .iter()
+            .map(|(name, values)| format!("{}={}", name, values.join(",")))
+            .collect::<Vec<_>>();
+
+        write!(f, "{}", filters.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::FilterValues;
+
+    #[test]
+    fn parses_name_and_comma_separated_values() {
+        let filters =
+            Ec2Filters::new(&[String::from("tag:Env=prod,staging")], &Config::default()).unwrap();
+
+        assert_eq!(
+            filters.0.get("tag:Env"),
+            Some(&vec![String::from("prod"), String::from("staging")])
+        );
+    }
+
+    #[test]
+    fn rejects_filters_without_values() {
+        assert!(Ec2Filters::new(&[String::from("vpc-id")], &Config::default()).is_err());
+        assert!(Ec2Filters::new(&[String::from("vpc-id=")], &Config::default()).is_err());
+    }
+
+    #[test]
+    fn args_override_default_filters() {
+        let config = Config {
+            default_filters: Some(BTreeMap::from([
+                (
+                    String::from("instance-state-name"),
+                    FilterValues::One(String::from("running")),
+                ),
+                (
+                    String::from("tag:Team"),
+                    FilterValues::Many(vec![String::from("core")]),
+                ),
+            ])),
+            ..Default::default()
+        };
+
+        let filters =
+            Ec2Filters::new(&[String::from("instance-state-name=stopped")], &config).unwrap();
+
+        assert_eq!(
+            filters.0.get("instance-state-name"),
+            Some(&vec![String::from("stopped")])
+        );
+        assert_eq!(filters.0.get("tag:Team"), Some(&vec![String::from("core")]));
+    }
+
+    #[test]
+    fn displays_in_command_line_form() {
+        let filters = Ec2Filters::new(
+            &[
+                String::from("tag:Env=prod,staging"),
+                String::from("instance-state-name=running"),
+            ],
+            &Config::default(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            filters.to_string(),
+            "instance-state-name=running tag:Env=prod,staging"
+        );
+    }
+}
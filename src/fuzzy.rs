@@ -0,0 +1,134 @@
+/*
+ * fzf style fuzzy matching. the pattern has to appear in the text as a subsequence, and the
+ * alignment with the best score wins: consecutive matches & matches at word boundaries score
+ * higher, gaps between matched characters are penalised.
+ */
+
+pub const SCORE_MATCH: i64 = 16;
+const BONUS_BOUNDARY: i64 = 8;
+const BONUS_CONSECUTIVE: i64 = 8;
+const PENALTY_GAP_START: i64 = -3;
+const PENALTY_GAP_EXTENSION: i64 = -1;
+
+/// scores `text` against a lowercased `pattern`, returning the score & the char positions
+/// of the matched characters, or None if `pattern` isn't a subsequence of `text`
+pub fn score(pattern: &str, text: &str) -> Option<(i64, Vec<usize>)> {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text
+        .chars()
+        .map(|c| c.to_lowercase().next().unwrap_or(c))
+        .collect::<Vec<_>>();
+
+    if pattern.is_empty() {
+        return Some((0, Vec::new()));
+    }
+    if pattern.len() > text.len() {
+        return None;
+    }
+
+    /* scores[i][j]: best score of pattern[..=i] with pattern[i] matched at text[j].
+     * previous[i][j]: where pattern[i - 1] was matched in that alignment */
+    let mut scores = vec![vec![None; text.len()]; pattern.len()];
+    let mut previous = vec![vec![0; text.len()]; pattern.len()];
+
+    for (j, c) in text.iter().enumerate() {
+        if *c == pattern[0] {
+            scores[0][j] = Some(SCORE_MATCH + bonus(&text, j));
+        }
+    }
+
+    for i in 1..pattern.len() {
+        /* best alignment of pattern[..i] ending at least two chars before j, gap included */
+        let mut gapped: Option<(i64, usize)> = None;
+        for j in 1..text.len() {
+            if j >= 2 {
+                let extended = gapped.map(|(score, k)| (score + PENALTY_GAP_EXTENSION, k));
+                let started = scores[i - 1][j - 2].map(|score| (score + PENALTY_GAP_START, j - 2));
+                gapped = match (extended, started) {
+                    (Some(extended), Some(started)) if started.0 >= extended.0 => Some(started),
+                    (Some(extended), _) => Some(extended),
+                    (None, started) => started,
+                };
+            }
+
+            if text[j] != pattern[i] {
+                continue;
+            }
+
+            let consecutive = scores[i - 1][j - 1].map(|score| (score + BONUS_CONSECUTIVE, j - 1));
+            let best = match (consecutive, gapped) {
+                (Some(consecutive), Some(gapped)) if gapped.0 > consecutive.0 => Some(gapped),
+                (Some(consecutive), _) => Some(consecutive),
+                (None, gapped) => gapped,
+            };
+
+            if let Some((score, k)) = best {
+                scores[i][j] = Some(score + SCORE_MATCH + bonus(&text, j));
+                previous[i][j] = k;
+            }
+        }
+    }
+
+    let last = pattern.len() - 1;
+    let (mut j, best) = scores[last]
+        .iter()
+        .enumerate()
+        .filter_map(|(j, score)| score.map(|score| (j, score)))
+        .max_by_key(|(j, score)| (*score, std::cmp::Reverse(*j)))?;
+
+    let mut positions = vec![0; pattern.len()];
+    for i in (0..pattern.len()).rev() {
+        positions[i] = j;
+        j = previous[i][j];
+    }
+
+    Some((best, positions))
+}
+
+/* matches at the start of the text or of a word score higher */
+fn bonus(text: &[char], j: usize) -> i64 {
+    match j == 0 || !text[j - 1].is_alphanumeric() {
+        true => BONUS_BOUNDARY,
+        false => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requires_a_subsequence() {
+        assert!(score("apd", "api-prod").is_some());
+        assert!(score("dpa", "api-prod").is_none());
+        assert!(score("api-prod-1", "api-prod").is_none());
+    }
+
+    #[test]
+    fn is_case_insensitive() {
+        assert!(score("api", "API-Gateway").is_some());
+    }
+
+    #[test]
+    fn contiguous_matches_rank_ahead_of_scattered_ones() {
+        let (contiguous, _) = score("api-prod", "service-api-production-3").unwrap();
+        let (scattered, _) = score("api-prod", "api-gateway-proxy-dev").unwrap();
+        assert!(contiguous > scattered);
+    }
+
+    #[test]
+    fn word_boundaries_rank_ahead_of_mid_word_matches() {
+        let (boundary, _) = score("prod", "api-prod").unwrap();
+        let (mid_word, _) = score("prod", "apiprod").unwrap();
+        assert!(boundary > mid_word);
+    }
+
+    #[test]
+    fn positions_point_at_the_best_alignment() {
+        let (_, positions) = score("api-prod", "service-api-production-3").unwrap();
+        assert_eq!(positions, vec![8, 9, 10, 11, 12, 13, 14, 15]);
+
+        let (_, positions) = score("sp3", "service-api-production-3").unwrap();
+        assert_eq!(positions, vec![0, 12, 23]);
+    }
+}
@@ -1,89 +1,678 @@
-use anyhow::Result;
-use aws_sdk_ec2::types::Instance;
+use std::collections::BTreeMap;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use aws_config::environment::region::EnvironmentVariableRegionProvider;
+use aws_config::meta::region::RegionProviderChain;
+use aws_config::profile::ProfileFileRegionProvider;
+use aws_config::{Region, SdkConfig};
+use aws_sdk_ec2::primitives::DateTimeFormat;
+use aws_sdk_ec2::types::{Instance, Reservation};
 use serde::{Deserialize, Serialize};
+use tokio::task::JoinSet;
 
+use crate::cache;
+use crate::clock;
+use crate::config::Config;
+use crate::credentials;
+use crate::filters::Ec2Filters;
+use crate::fuzzy;
 use crate::opts::Opts;
+use crate::search::Query;
 
-pub const CACHE_FILE: &str = "/tmp/blaze_ssh_cache.json";
+/// set on the detached process refreshing stale caches in the background
+pub const BACKGROUND_REFRESH_ENV: &str = "BLAZE_SSH_BACKGROUND_REFRESH";
+const MAX_CACHE_KEY_LENGTH: usize = 128;
+/* DescribeInstances accepts page sizes between 5 & 1000 */
+pub const DEFAULT_PAGE_SIZE: i32 = 1000;
+const MIN_PAGE_SIZE: i32 = 5;
+/// width of the instance name column of display names
+pub const DISPLAY_NAME_LENGTH: usize = 32;
+/* roughly two matched characters worth of score */
+const CLEAR_WINNER_MARGIN: i64 = 2 * fuzzy::SCORE_MATCH;
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
 pub struct InstanceSet {
     pub instances: Vec<InstanceDetails>,
+    /// server side filters the instances were fetched with
+    #[serde(default)]
+    pub filters: Ec2Filters,
+    /// unix timestamp of the (oldest) remote fetch the instances come from
+    #[serde(default)]
+    pub fetched_at: Option<i64>,
+    /// served from a cache older than `cache-ttl`
+    #[serde(skip)]
+    pub stale: bool,
 }
 
 impl InstanceSet {
-    pub fn new(instances: Vec<InstanceDetails>) -> Result<Self> {
-        Ok(Self { instances })
+    /// seconds since the instances were fetched
+    pub fn age(&self) -> Option<i64> {
+        self.fetched_at.map(|fetched_at| clock::now() - fetched_at)
     }
 
-    async fn fetch_remote() -> Result<Self> {
-        let aws_config = aws_config::load_from_env().await;
-        let aws_client = aws_sdk_ec2::Client::new(&aws_config);
-        let response = aws_client.describe_instances().send().await?;
-        let instances = response
-            .reservations()
-            .iter()
-            .flat_map(|reservation| reservation.instances())
-            .map(InstanceDetails::from_instance)
-            .flat_map(Result::ok)
-            .collect::<Vec<_>>();
+    /// e.g. "fetched 5m ago", "fetched 2h ago (stale)"
+    pub fn age_label(&self) -> Option<String> {
+        self.age().map(|age| {
+            format!(
+                "fetched {} ago{}",
+                clock::format_age(age),
+                match self.stale {
+                    true => " (stale)",
+                    false => "",
+                }
+            )
+        })
+    }
+
+    /* the instances of every profile, & whether they were cut short by max-results */
+    async fn fetch_remote(
+        profiles: &[Option<String>],
+        filters: &Ec2Filters,
+        opts: &Opts,
+        config: &Config,
+        quiet: bool,
+    ) -> Result<Vec<(Self, bool)>> {
+        let page_size = Self::page_size(config);
+        let progress = FetchProgress::new(config.max_results, quiet);
+
+        let mut tasks = JoinSet::new();
+        for (profile_index, profile) in profiles.iter().enumerate() {
+            let aws_config = Self::load_aws_config(profile, config, !quiet).await?;
+            let regions = Self::resolve_regions(&aws_config, opts, config).await?;
+            for (region_index, region) in regions.into_iter().enumerate() {
+                let region_config = aws_config
+                    .to_builder()
+                    .region(Region::new(region.clone()))
+                    .build();
+                let profile = profile.clone();
+                let filters = filters.clone();
+                let progress = progress.clone();
+                tasks.spawn(async move {
+                    let client = aws_sdk_ec2::Client::new(&region_config);
+                    let instances = Self::fetch_region(
+                        &client, &region, profile, &filters, page_size, &progress,
+                    )
+                    .await;
+                    (profile_index, region_index, instances)
+                });
+            }
+        }
+
+        let mut fetched = Vec::new();
+        while let Some(result) = tasks.join_next().await {
+            let (profile_index, region_index, region_fetch) = result?;
+            fetched.push((profile_index, region_index, region_fetch?));
+        }
+        progress.finish();
+
+        let fetched_at = clock::now();
+        Ok(Self::merge_regions(profiles.len(), fetched)
+            .into_iter()
+            .map(|(instances, truncated)| {
+                let instance_set = InstanceSet {
+                    instances,
+                    filters: filters.clone(),
+                    fetched_at: Some(fetched_at),
+                    stale: false,
+                };
+                (instance_set, truncated)
+            })
+            .collect())
+    }
 
-        let instance_set = InstanceSet::new(instances)?;
-        instance_set.write()?;
+    /* the instances of every profile, in region order regardless of which region answered
+     * first, & whether any of its regions stopped early at max-results */
+    fn merge_regions(
+        profile_count: usize,
+        mut fetched: Vec<(usize, usize, RegionFetch)>,
+    ) -> Vec<(Vec<InstanceDetails>, bool)> {
+        fetched.sort_by_key(|(profile_index, region_index, _)| (*profile_index, *region_index));
+
+        let mut profile_fetches = vec![(Vec::new(), false); profile_count];
+        fetched
+            .into_iter()
+            .for_each(|(profile_index, _, (instances, truncated))| {
+                let (profile_instances, profile_truncated) = &mut profile_fetches[profile_index];
+                profile_instances.extend(instances);
+                *profile_truncated |= truncated;
+            });
+
+        profile_fetches
+    }
+
+    /* the configured page size, brought within the bounds DescribeInstances accepts */
+    fn page_size(config: &Config) -> i32 {
+        config
+            .page_size
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(MIN_PAGE_SIZE, DEFAULT_PAGE_SIZE)
+    }
+
+    /* names matching a configured account assume that account's role, anything else is
+     * treated as a named aws profile. `interactive` allows prompting for MFA codes */
+    pub async fn load_aws_config(
+        profile: &Option<String>,
+        config: &Config,
+        interactive: bool,
+    ) -> Result<SdkConfig> {
+        match profile {
+            Some(profile) if config.account(profile).is_some() => {
+                credentials::assume_role_config(profile, config, interactive).await
+            }
+            Some(profile) => Ok(aws_config::from_env().profile_name(profile).load().await),
+            None => Ok(aws_config::load_from_env().await),
+        }
+    }
+
+    async fn fetch_region(
+        client: &aws_sdk_ec2::Client,
+        region: &str,
+        profile: Option<String>,
+        filters: &Ec2Filters,
+        page_size: i32,
+        progress: &FetchProgress,
+    ) -> Result<RegionFetch> {
+        let mut pages = client
+            .describe_instances()
+            .set_filters(match filters.is_empty() {
+                true => None,
+                false => Some(filters.to_sdk()),
+            })
+            .max_results(page_size)
+            .into_paginator()
+            .send();
+
+        let mut instances = Vec::new();
+        while let Some(page) = pages.next().await {
+            let page_instances = page?
+                .reservations()
+                .iter()
+                .flat_map(|reservation| {
+                    InstanceDetails::from_reservation(reservation, region, &profile)
+                })
+                .collect::<Vec<_>>();
+
+            progress.add(page_instances.len());
+            instances.extend(page_instances);
+
+            if progress.exhausted() {
+                return Ok((instances, true));
+            }
+        }
 
-        Ok(instance_set)
+        Ok((instances, false))
+    }
+
+    /* --profile flags take precedence over the `profiles` config key. no profiles at all means
+     * whatever the environment (AWS_PROFILE etc.) resolves to */
+    fn resolve_profiles(opts: &Opts, config: &Config) -> Vec<Option<String>> {
+        let requested = match opts.profiles.is_empty() {
+            true => config.profiles.clone().unwrap_or_default(),
+            false => opts.profiles.clone(),
+        };
+
+        match requested.is_empty() {
+            true => vec![None],
+            false => {
+                let mut profiles = Vec::new();
+                requested.into_iter().map(Some).for_each(|profile| {
+                    if !profiles.contains(&profile) {
+                        profiles.push(profile);
+                    }
+                });
+                profiles
+            }
+        }
+    }
+
+    fn requested_regions(opts: &Opts, config: &Config) -> Vec<String> {
+        match opts.regions.is_empty() {
+            true => config.regions.clone().unwrap_or_default(),
+            false => opts.regions.clone(),
+        }
+    }
+
+    /* --region flags take precedence over the `regions` config key. "all" expands to every
+     * region enabled for the account, and no regions at all means the sdk's default region */
+    async fn resolve_regions(
+        aws_config: &SdkConfig,
+        opts: &Opts,
+        config: &Config,
+    ) -> Result<Vec<String>> {
+        let requested = Self::requested_regions(opts, config);
+        if requested.iter().any(|region| region == "all") {
+            let response = aws_sdk_ec2::Client::new(aws_config)
+                .describe_regions()
+                .send()
+                .await?;
+
+            return Ok(response
+                .regions()
+                .iter()
+                .filter_map(|region| region.region_name.clone())
+                .collect());
+        }
+
+        match requested.is_empty() {
+            true => match aws_config.region() {
+                Some(region) => Ok(vec![region.to_string()]),
+                None => Err(anyhow!(
+                    "No AWS region configured. Please use --region, the `regions` config key or AWS_REGION"
+                )),
+            },
+            false => {
+                let mut regions = Vec::new();
+                requested.into_iter().for_each(|region| {
+                    if !regions.contains(&region) {
+                        regions.push(region);
+                    }
+                });
+                Ok(regions)
+            }
+        }
     }
 
     pub fn is_non_selectable(&self) -> bool {
         self.instances.len() == 1
     }
 
-    pub async fn fetch(opts: &Opts) -> Result<Self> {
-        match opts.no_cache {
-            false => {
-                let cache_result = std::fs::read_to_string(CACHE_FILE);
-                match cache_result {
-                    Ok(cache) => serde_json::from_str(&cache).map_err(|e| e.into()),
-                    Err(_e) => Self::fetch_remote().await,
+    /* every profile & region set is cached on its own, so switching accounts or regions never
+     * serves another account's instances. only profiles without a usable cache are fetched
+     * remotely, and a cache fetched with different server side filters is never reused. caches
+     * older than `cache-ttl` are refetched, unless `stale-while-revalidate` allows serving them
+     * while a refresh runs in the background (see `stale`) */
+    pub async fn fetch(opts: &Opts, config: &Config) -> Result<Self> {
+        Self::fetch_with(opts, config, opts.no_cache, false).await
+    }
+
+    /// refetches every profile & rewrites their caches
+    pub async fn refresh(opts: &Opts, config: &Config) -> Result<Self> {
+        Self::fetch_with(opts, config, true, false).await
+    }
+
+    /// refreshes without progress output or MFA prompts, for refreshes running behind the
+    /// picker or in the background where the terminal isn't ours to use
+    pub async fn refresh_quietly(opts: &Opts, config: &Config) -> Result<Self> {
+        Self::fetch_with(opts, config, true, true).await
+    }
+
+    async fn fetch_with(opts: &Opts, config: &Config, no_cache: bool, quiet: bool) -> Result<Self> {
+        let profiles = Self::resolve_profiles(opts, config);
+        let filters = Ec2Filters::new(&opts.filters(), config)?;
+        let serve_stale = config.stale_while_revalidate.unwrap_or(false);
+        let mut cache_keys = Vec::new();
+        for profile in profiles.iter() {
+            cache_keys.push(CacheKey::resolve(profile.as_deref(), opts, config).await);
+        }
+
+        let mut profile_sets = cache_keys
+            .iter()
+            .map(|cache_key| match no_cache {
+                false => Self::usable_cache(
+                    Self::read_cache(cache_key).ok().flatten(),
+                    &filters,
+                    config.cache_ttl,
+                    serve_stale,
+                ),
+                true => None,
+            })
+            .collect::<Vec<_>>();
+
+        let uncached = profiles
+            .iter()
+            .zip(profile_sets.iter())
+            .filter(|(_, cached)| cached.is_none())
+            .map(|(profile, _)| profile.clone())
+            .collect::<Vec<_>>();
+
+        if !uncached.is_empty() {
+            let mut fetched = Self::fetch_remote(&uncached, &filters, opts, config, quiet)
+                .await?
+                .into_iter();
+            for (cache_key, slot) in cache_keys.iter().zip(profile_sets.iter_mut()) {
+                if slot.is_some() {
+                    continue;
+                }
+
+                let Some((instance_set, truncated)) = fetched.next() else {
+                    break;
+                };
+                /* a set cut short by max-results would be served as the whole inventory later */
+                if !truncated {
+                    instance_set.write(cache_key)?;
                 }
+                *slot = Some(instance_set);
             }
-            true => Self::fetch_remote().await,
+        }
+
+        let profile_sets = profile_sets.into_iter().flatten().collect::<Vec<_>>();
+        let stale = profile_sets.iter().any(|instance_set| instance_set.stale);
+        let fetched_at = profile_sets
+            .iter()
+            .filter_map(|instance_set| instance_set.fetched_at)
+            .min();
+        let mut instances = profile_sets
+            .into_iter()
+            .flat_map(|instance_set| instance_set.instances)
+            .collect::<Vec<_>>();
+
+        if let Some(max_results) = config.max_results {
+            if Self::truncate_to(&mut instances, max_results) && !quiet {
+                eprintln!("instance list truncated to {} (max-results)", max_results);
+            }
+        }
+
+        Ok(Self {
+            instances,
+            filters,
+            fetched_at,
+            stale,
+        })
+    }
+
+    /* a cache fetched with other server side filters is never reused, & an expired one only
+     * when stale caches may be served */
+    fn usable_cache(
+        cached: Option<Self>,
+        filters: &Ec2Filters,
+        ttl: Option<u64>,
+        serve_stale: bool,
+    ) -> Option<Self> {
+        cached
+            .filter(|cached| &cached.filters == filters)
+            .map(|cached| InstanceSet {
+                stale: cached.is_expired(ttl),
+                ..cached
+            })
+            .filter(|cached| !cached.stale || serve_stale)
+    }
+
+    /* caches without a timestamp predate cache-ttl & count as expired once a ttl is set */
+    pub fn is_expired(&self, ttl: Option<u64>) -> bool {
+        match (ttl, self.age()) {
+            (None, _) => false,
+            (Some(_), None) => true,
+            (Some(ttl), Some(age)) => age >= ttl as i64,
         }
     }
 
-    pub fn write(&self) -> Result<()> {
-        std::fs::write(CACHE_FILE, serde_json::to_string(self)?)?;
+    /// refreshes the caches from a detached copy of the current process, so that the refresh
+    /// outlives this process & the next run finds fresh caches
+    pub fn spawn_background_refresh() -> Result<()> {
+        Command::new(std::env::current_exe()?)
+            .args(std::env::args_os().skip(1))
+            .env(BACKGROUND_REFRESH_ENV, "1")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
 
         Ok(())
     }
 
-    pub fn filter(&self, search: &Option<String>) -> Result<Self> {
-        if search.is_none() {
-            Self::new(self.instances.clone())
-        } else {
-            Self::new(
-                self.instances
-                    .clone()
-                    .into_iter()
-                    .filter(|instance| {
-                        instance
-                            .instance_name
-                            .clone()
-                            .unwrap_or("".to_string())
-                            .contains(search.clone().unwrap().as_str())
-                    })
-                    .collect::<Vec<_>>(),
-            )
+    /* whether there were more than `max_results` instances to drop */
+    fn truncate_to(instances: &mut Vec<InstanceDetails>, max_results: usize) -> bool {
+        let truncated = instances.len() > max_results;
+        instances.truncate(max_results);
+        truncated
+    }
+
+    fn instances_cache_dir() -> Result<PathBuf> {
+        Ok(cache::cache_dir()?.join("instances"))
+    }
+
+    fn read_cache(cache_key: &CacheKey) -> Result<Option<Self>> {
+        cache::read(&cache_key.path()?)
+    }
+
+    pub fn write(&self, cache_key: &CacheKey) -> Result<()> {
+        cache::write(&cache_key.path()?, self)
+    }
+
+    /// every readable cached instance list, across all accounts & region sets
+    pub fn cached() -> Result<Vec<CacheEntry>> {
+        Self::cached_in(&Self::instances_cache_dir()?)
+    }
+
+    fn cached_in(dir: &Path) -> Result<Vec<CacheEntry>> {
+        let mut entries = Vec::new();
+        for profile_dir in Self::cache_dir_entries(dir)? {
+            for path in Self::cache_dir_entries(&profile_dir)? {
+                if path.extension().is_none_or(|extension| extension != "json") {
+                    continue;
+                }
+
+                if let Some(instance_set) = cache::read::<Self>(&path)? {
+                    entries.push(CacheEntry {
+                        profile: profile_dir
+                            .file_name()
+                            .map(|name| name.to_string_lossy().to_string())
+                            .unwrap_or_default(),
+                        path,
+                        instance_set,
+                    });
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// removes every cached instance list, returning how many there were
+    pub fn clear_cache() -> Result<usize> {
+        Self::clear_cache_in(&Self::instances_cache_dir()?)
+    }
+
+    fn clear_cache_in(dir: &Path) -> Result<usize> {
+        let cleared = Self::cached_in(dir)?.len();
+        match std::fs::remove_dir_all(dir) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(cleared),
+        }
+    }
+
+    /* sorted paths inside a cache directory, none if it doesn't exist yet */
+    fn cache_dir_entries(dir: &Path) -> Result<Vec<PathBuf>> {
+        let mut paths = match std::fs::read_dir(dir) {
+            Ok(entries) => entries
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<std::io::Result<Vec<_>>>()?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        paths.sort();
+
+        Ok(paths)
+    }
+
+    /// instances matching the query, best matches first. instances with equal scores keep
+    /// their original order, so non fuzzy queries never reorder the set
+    pub fn filter(&self, query: &Query) -> Result<Self> {
+        let mut scored = self
+            .instances
+            .iter()
+            .filter_map(|instance| Some((query.score(instance)?, instance.clone())))
+            .collect::<Vec<_>>();
+
+        scored.sort_by_key(|(score, _)| std::cmp::Reverse(*score));
+        Ok(Self {
+            instances: scored.into_iter().map(|(_, instance)| instance).collect(),
+            filters: self.filters.clone(),
+            fetched_at: self.fetched_at,
+            stale: self.stale,
+        })
+    }
+
+    /// whether the best match of a ranked set outscores the runner up by a clear margin
+    pub fn has_clear_winner(&self, query: &Query) -> bool {
+        let mut scores = self
+            .instances
+            .iter()
+            .filter_map(|instance| query.score(instance));
+
+        match (scores.next(), scores.next()) {
+            (Some(best), Some(runner_up)) => best - runner_up >= CLEAR_WINNER_MARGIN,
+            _ => false,
+        }
+    }
+}
+
+/// identifies a cached instance list by the credentials & regions it was fetched with, so that
+/// a changed AWS_PROFILE, AWS_REGION or account never serves another inventory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheKey {
+    /// the (file name safe) account, profile or environment credentials, e.g. `account-prod`
+    pub profile: String,
+    /// the requested regions, or else the region the sdk defaults to
+    pub regions: Vec<String>,
+}
+
+impl CacheKey {
+    pub async fn resolve(profile: Option<&str>, opts: &Opts, config: &Config) -> Self {
+        let mut regions = InstanceSet::requested_regions(opts, config);
+        if regions.is_empty() {
+            /* the account's region comes from the profile its role chain starts from */
+            let region_profile = match profile {
+                Some(profile) if config.account(profile).is_some() => {
+                    credentials::source_profile(profile, config)
+                }
+                profile => profile.map(String::from),
+            };
+            regions.extend(Self::default_region(region_profile).await);
+        }
+
+        Self {
+            profile: Self::profile_dir(profile, config, |name| std::env::var(name).ok()),
+            regions,
+        }
+    }
+
+    /* the region the sdk's environment & profile providers resolve to. the instance metadata
+     * service is skipped, looking it up on every cache read would stall off ec2 */
+    async fn default_region(profile: Option<String>) -> Option<String> {
+        let profile_region = match profile {
+            Some(profile) => ProfileFileRegionProvider::builder()
+                .profile_name(profile)
+                .build(),
+            None => ProfileFileRegionProvider::new(),
+        };
+
+        RegionProviderChain::first_try(EnvironmentVariableRegionProvider::new())
+            .or_else(profile_region)
+            .region()
+            .await
+            .map(|region| region.to_string())
+    }
+
+    /* accounts, named profiles & environment credentials are prefixed, so that none of them
+     * can share a directory whatever they're called. `env` looks up environment variables */
+    fn profile_dir(
+        profile: Option<&str>,
+        config: &Config,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> String {
+        let env = |name: &str| env(name).filter(|value| !value.is_empty());
+        let profile_dir = match profile {
+            Some(profile) if config.account(profile).is_some() => format!("account-{}", profile),
+            Some(profile) => format!("profile-{}", profile),
+            /* static credentials in the environment take precedence over any profile */
+            None => match (env("AWS_ACCESS_KEY_ID"), env("AWS_PROFILE")) {
+                (Some(access_key_id), _) => format!("env-{}", cache::digest(&access_key_id)),
+                (None, Some(profile)) => format!("profile-{}", profile),
+                (None, None) => String::from("profile-default"),
+            },
+        };
+
+        cache::file_name(&profile_dir)
+    }
+
+    /// e.g. `~/.cache/blaze-ssh/instances/profile-production/eu-west-1_us-east-1.json`
+    pub fn path(&self) -> Result<PathBuf> {
+        Ok(InstanceSet::instances_cache_dir()?
+            .join(&self.profile)
+            .join(format!("{}.json", self.regions_key())))
+    }
+
+    fn regions_key(&self) -> String {
+        let mut regions = self.regions.clone();
+        regions.sort();
+        regions.dedup();
+
+        let regions_key = match regions.is_empty() {
+            true => String::from("default"),
+            false => cache::file_name(&regions.join("_")),
+        };
+        /* stay well within file name length limits when many regions are listed */
+        match regions_key.len() > MAX_CACHE_KEY_LENGTH {
+            true => format!("regions-{}", cache::digest(&regions_key)),
+            false => regions_key,
         }
     }
 }
 
+/// a cached instance list & where it is stored
+#[derive(Debug, Clone)]
+pub struct CacheEntry {
+    /// the (file name safe) account or profile the instances were fetched with
+    pub profile: String,
+    pub path: PathBuf,
+    pub instance_set: InstanceSet,
+}
+
+impl CacheEntry {
+    /// the instances of every entry as one pretty printed json array
+    pub fn export(entries: Vec<Self>, mut writer: impl Write) -> Result<()> {
+        let instances = entries
+            .into_iter()
+            .flat_map(|entry| entry.instance_set.instances)
+            .collect::<Vec<_>>();
+        serde_json::to_writer_pretty(&mut writer, &instances)?;
+        writeln!(writer)?;
+
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash, Default)]
 pub struct InstanceDetails {
     pub public_ip: Option<String>,
     pub private_ip: Option<String>,
     pub instance_id: Option<String>,
     pub instance_name: Option<String>,
+    #[serde(default)]
+    pub region: Option<String>,
+    #[serde(default)]
+    pub profile: Option<String>,
+    #[serde(default)]
+    pub account_id: Option<String>,
+    #[serde(default)]
+    pub state: Option<String>,
+    #[serde(default)]
+    pub instance_type: Option<String>,
+    #[serde(default)]
+    pub availability_zone: Option<String>,
+    /// RFC 3339 timestamp
+    #[serde(default)]
+    pub launch_time: Option<String>,
+    #[serde(default)]
+    pub platform: Option<String>,
+    #[serde(default)]
+    pub image_id: Option<String>,
+    #[serde(default)]
+    pub key_name: Option<String>,
+    #[serde(default)]
+    pub vpc_id: Option<String>,
+    #[serde(default)]
+    pub subnet_id: Option<String>,
+    #[serde(default)]
+    pub ipv6_addresses: Vec<String>,
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
 }
 
 impl InstanceDetails {
@@ -94,9 +683,55 @@ impl InstanceDetails {
             private_ip: instance_clone.private_ip_address,
             instance_id: instance_clone.instance_id,
             instance_name: Self::extract_instance_name(&instance.clone()),
+            region: None,
+            profile: None,
+            account_id: None,
+            state: instance
+                .state()
+                .and_then(|state| state.name())
+                .map(|name| name.as_str().to_string()),
+            instance_type: instance
+                .instance_type()
+                .map(|instance_type| instance_type.as_str().to_string()),
+            availability_zone: instance
+                .placement()
+                .and_then(|placement| placement.availability_zone.clone()),
+            launch_time: instance
+                .launch_time()
+                .and_then(|launch_time| launch_time.fmt(DateTimeFormat::DateTime).ok()),
+            platform: instance.platform_details().map(String::from).or(instance
+                .platform()
+                .map(|platform| platform.as_str().to_string())),
+            image_id: instance_clone.image_id,
+            key_name: instance_clone.key_name,
+            vpc_id: instance_clone.vpc_id,
+            subnet_id: instance_clone.subnet_id,
+            ipv6_addresses: Self::extract_ipv6_addresses(instance),
+            tags: Self::extract_tags(instance),
         })
     }
 
+    /// the instances of a reservation, tagged with where they were found & the reservation's
+    /// owner as their account id
+    pub fn from_reservation(
+        reservation: &Reservation,
+        region: &str,
+        profile: &Option<String>,
+    ) -> Vec<Self> {
+        reservation
+            .instances()
+            .iter()
+            .map(Self::from_instance)
+            .flat_map(Result::ok)
+            .map(|instance| InstanceDetails {
+                region: Some(region.to_string()),
+                profile: profile.clone(),
+                account_id: reservation.owner_id.clone(),
+                ..instance
+            })
+            .collect()
+    }
+
     pub fn is_empty(&self) -> bool {
         self.public_ip.is_none()
             && self.private_ip.is_none()
@@ -104,14 +739,89 @@ impl InstanceDetails {
             && self.instance_name.is_none()
     }
 
+    /// attributes matched by unprefixed search terms
+    pub fn searchable_fields(&self) -> Vec<String> {
+        [
+            &self.instance_name,
+            &self.instance_id,
+            &self.private_ip,
+            &self.public_ip,
+            &self.region,
+            &self.account_label(),
+            &self.state,
+            &self.instance_type,
+            &self.availability_zone,
+            &self.platform,
+            &self.image_id,
+            &self.key_name,
+            &self.vpc_id,
+            &self.subnet_id,
+        ]
+        .into_iter()
+        .flatten()
+        .cloned()
+        .chain(self.ipv6_addresses.iter().cloned())
+        .collect()
+    }
+
+    /// label / value pairs describing every captured attribute of the instance
+    pub fn details(&self) -> Vec<(&'static str, String)> {
+        let none = || "None".to_string();
+        vec![
+            ("Name", self.instance_name.clone().unwrap_or_else(none)),
+            ("Instance ID", self.instance_id.clone().unwrap_or_else(none)),
+            ("State", self.state.clone().unwrap_or_else(none)),
+            ("Type", self.instance_type.clone().unwrap_or_else(none)),
+            ("Platform", self.platform.clone().unwrap_or_else(none)),
+            ("AMI", self.image_id.clone().unwrap_or_else(none)),
+            ("Key Pair", self.key_name.clone().unwrap_or_else(none)),
+            ("Launched", self.launch_time.clone().unwrap_or_else(none)),
+            ("Region", self.region.clone().unwrap_or_else(none)),
+            ("AZ", self.availability_zone.clone().unwrap_or_else(none)),
+            ("Account", self.account_label().unwrap_or_else(none)),
+            ("VPC", self.vpc_id.clone().unwrap_or_else(none)),
+            ("Subnet", self.subnet_id.clone().unwrap_or_else(none)),
+            ("Private IP", self.private_ip.clone().unwrap_or_else(none)),
+            ("Public IP", self.public_ip.clone().unwrap_or_else(none)),
+            (
+                "IPv6",
+                match self.ipv6_addresses.is_empty() {
+                    true => none(),
+                    false => self.ipv6_addresses.join(", "),
+                },
+            ),
+            (
+                "Tags",
+                match self.tags.is_empty() {
+                    true => none(),
+                    false => self
+                        .tags
+                        .iter()
+                        .map(|(key, value)| format!("{}={}", key, value))
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                },
+            ),
+        ]
+    }
+
+    /* the profile an instance was discovered through, falling back to its account id */
+    pub fn account_label(&self) -> Option<String> {
+        self.profile.clone().or(self.account_id.clone())
+    }
+
     pub fn display_name(&self) -> Result<String> {
         let cloned_instance = self.clone();
         Ok(format!(
-            "{:<32} | priv_ip: {:>16} | pub_ip: {:>16} | {:<32}",
+            "{:<32} | {:<13} | {:<12} | priv_ip: {:>16} | pub_ip: {:>16} | {:<20} | {:<16} | {:<16}",
             Self::truncate_string(cloned_instance.instance_name.unwrap_or("None".to_string())),
+            cloned_instance.state.unwrap_or("None".to_string()),
+            cloned_instance.instance_type.unwrap_or("None".to_string()),
             cloned_instance.private_ip.unwrap_or("None".to_string()),
             cloned_instance.public_ip.unwrap_or("None".to_string()),
-            cloned_instance.instance_id.unwrap_or("None".to_string())
+            cloned_instance.instance_id.unwrap_or("None".to_string()),
+            cloned_instance.region.unwrap_or("None".to_string()),
+            self.account_label().unwrap_or("None".to_string())
         ))
     }
 
@@ -124,11 +834,500 @@ impl InstanceDetails {
             .and_then(|tag| tag.value)
     }
 
+    fn extract_tags(instance: &Instance) -> BTreeMap<String, String> {
+        instance
+            .tags()
+            .iter()
+            .filter_map(|tag| Some((tag.key.clone()?, tag.value.clone().unwrap_or_default())))
+            .collect()
+    }
+
+    /* the primary ipv6 address first, followed by those of every network interface */
+    fn extract_ipv6_addresses(instance: &Instance) -> Vec<String> {
+        let mut addresses = instance
+            .ipv6_address()
+            .map(String::from)
+            .into_iter()
+            .collect::<Vec<_>>();
+
+        instance
+            .network_interfaces()
+            .iter()
+            .flat_map(|interface| interface.ipv6_addresses())
+            .filter_map(|address| address.ipv6_address())
+            .for_each(|address| {
+                if !addresses.iter().any(|existing| existing == address) {
+                    addresses.push(address.to_string());
+                }
+            });
+
+        addresses
+    }
+
     fn truncate_string(input: String) -> String {
-        if input.len() <= 32 {
+        if input.chars().count() <= DISPLAY_NAME_LENGTH {
             return input;
         }
 
-        format!("{}...", &input[..29])
+        format!(
+            "{}...",
+            input
+                .chars()
+                .take(DISPLAY_NAME_LENGTH - 3)
+                .collect::<String>()
+        )
+    }
+}
+
+/* the instances of a region & whether fetching them stopped early at max-results */
+type RegionFetch = (Vec<InstanceDetails>, bool);
+
+/* shared between concurrent region fetches so that the stderr progress line & the max-results
+ * bound account for every region at once */
+#[derive(Debug, Clone)]
+struct FetchProgress {
+    fetched: Arc<AtomicUsize>,
+    exhausted: Arc<AtomicBool>,
+    max_results: Option<usize>,
+    visible: bool,
+}
+
+impl FetchProgress {
+    fn new(max_results: Option<usize>, quiet: bool) -> Self {
+        Self {
+            fetched: Arc::new(AtomicUsize::new(0)),
+            exhausted: Arc::new(AtomicBool::new(false)),
+            max_results,
+            visible: !quiet && std::io::stderr().is_terminal(),
+        }
+    }
+
+    fn add(&self, count: usize) {
+        let fetched = self.fetched.fetch_add(count, Ordering::SeqCst) + count;
+        if let Some(max_results) = self.max_results {
+            if fetched >= max_results {
+                self.exhausted.store(true, Ordering::SeqCst);
+            }
+        }
+
+        if self.visible {
+            eprint!("\rfetching instances... {}", fetched);
+            let _ = std::io::stderr().flush();
+        }
+    }
+
+    fn exhausted(&self) -> bool {
+        self.exhausted.load(Ordering::SeqCst)
+    }
+
+    fn finish(&self) {
+        if self.visible {
+            eprint!("\r\x1b[2K");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Account;
+    use clap::Parser;
+
+    fn instance(id: &str) -> InstanceDetails {
+        InstanceDetails {
+            instance_id: Some(id.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn merges_regions_in_order_per_profile() {
+        let fetched = vec![
+            (1, 0, (vec![instance("i-p1-r0")], false)),
+            (0, 1, (vec![instance("i-p0-r1")], true)),
+            (
+                0,
+                0,
+                (vec![instance("i-p0-r0a"), instance("i-p0-r0b")], false),
+            ),
+            (1, 1, (vec![instance("i-p1-r1")], false)),
+        ];
+
+        assert_eq!(
+            InstanceSet::merge_regions(3, fetched),
+            vec![
+                (
+                    vec![
+                        instance("i-p0-r0a"),
+                        instance("i-p0-r0b"),
+                        instance("i-p0-r1")
+                    ],
+                    true
+                ),
+                (vec![instance("i-p1-r0"), instance("i-p1-r1")], false),
+                (Vec::new(), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn progress_is_exhausted_across_regions() {
+        let progress = FetchProgress::new(Some(5), true);
+        let region = progress.clone();
+
+        progress.add(3);
+        assert!(!region.exhausted());
+        region.add(2);
+        assert!(progress.exhausted());
+    }
+
+    #[test]
+    fn profile_flags_override_the_config() {
+        let config = Config {
+            profiles: Some(vec![String::from("staging"), String::from("production")]),
+            ..Default::default()
+        };
+        let profiles = |args: &[&str], config: &Config| {
+            let opts = Opts::parse_from(["blssh"].iter().chain(args).chain(&["list"]));
+            InstanceSet::resolve_profiles(&opts, config)
+        };
+        let some = |profile: &str| Some(profile.to_string());
+
+        assert_eq!(profiles(&[], &Config::default()), vec![None]);
+        assert_eq!(
+            profiles(&[], &config),
+            vec![some("staging"), some("production")]
+        );
+        assert_eq!(
+            profiles(
+                &["--profile", "dev", "--profile", "ops", "--profile", "dev"],
+                &config
+            ),
+            vec![some("dev"), some("ops")]
+        );
+    }
+
+    #[test]
+    fn account_id_comes_from_the_reservation_owner() {
+        let reservation = Reservation::builder()
+            .owner_id("111111111111")
+            .instances(Instance::builder().instance_id("i-1").build())
+            .instances(Instance::builder().instance_id("i-2").build())
+            .build();
+
+        let instances = InstanceDetails::from_reservation(&reservation, "eu-west-1", &None);
+        assert_eq!(instances.len(), 2);
+        assert!(instances.iter().all(|instance| {
+            instance.account_id.as_deref() == Some("111111111111")
+                && instance.region.as_deref() == Some("eu-west-1")
+                && instance.profile.is_none()
+        }));
+        assert_eq!(
+            instances[0].account_label().as_deref(),
+            Some("111111111111")
+        );
+
+        let instances = InstanceDetails::from_reservation(
+            &reservation,
+            "eu-west-1",
+            &Some(String::from("production")),
+        );
+        assert_eq!(instances[1].account_label().as_deref(), Some("production"));
+        assert_eq!(InstanceDetails::default().account_label(), None);
+    }
+
+    #[test]
+    fn captures_every_instance_attribute() {
+        use aws_sdk_ec2::primitives::DateTime;
+        use aws_sdk_ec2::types::{
+            InstanceIpv6Address, InstanceNetworkInterface, InstanceState, InstanceStateName,
+            InstanceType, Placement, Tag,
+        };
+
+        let ipv6 = |address: &str| InstanceIpv6Address::builder().ipv6_address(address).build();
+        let tag = |key: &str, value: &str| Tag::builder().key(key).value(value).build();
+        let instance = Instance::builder()
+            .instance_id("i-0abc")
+            .public_ip_address("1.2.3.4")
+            .private_ip_address("10.0.0.1")
+            .state(
+                InstanceState::builder()
+                    .name(InstanceStateName::Running)
+                    .build(),
+            )
+            .instance_type(InstanceType::T3Micro)
+            .placement(Placement::builder().availability_zone("eu-west-1a").build())
+            .launch_time(DateTime::from_secs(1_700_000_000))
+            .platform_details("Linux/UNIX")
+            .image_id("ami-0ubuntu")
+            .key_name("ops")
+            .vpc_id("vpc-1")
+            .subnet_id("subnet-1")
+            .ipv6_address("2001:db8::1")
+            .network_interfaces(
+                InstanceNetworkInterface::builder()
+                    .ipv6_addresses(ipv6("2001:db8::1"))
+                    .ipv6_addresses(ipv6("2001:db8::2"))
+                    .build(),
+            )
+            .tags(tag("Name", "web-1"))
+            .tags(tag("Env", "prod"))
+            .build();
+
+        let details = InstanceDetails::from_instance(&instance).unwrap();
+        assert_eq!(
+            details,
+            InstanceDetails {
+                public_ip: Some(String::from("1.2.3.4")),
+                private_ip: Some(String::from("10.0.0.1")),
+                instance_id: Some(String::from("i-0abc")),
+                instance_name: Some(String::from("web-1")),
+                region: None,
+                profile: None,
+                account_id: None,
+                state: Some(String::from("running")),
+                instance_type: Some(String::from("t3.micro")),
+                availability_zone: Some(String::from("eu-west-1a")),
+                launch_time: Some(String::from("2023-11-14T22:13:20Z")),
+                platform: Some(String::from("Linux/UNIX")),
+                image_id: Some(String::from("ami-0ubuntu")),
+                key_name: Some(String::from("ops")),
+                vpc_id: Some(String::from("vpc-1")),
+                subnet_id: Some(String::from("subnet-1")),
+                ipv6_addresses: vec![String::from("2001:db8::1"), String::from("2001:db8::2")],
+                tags: BTreeMap::from([
+                    (String::from("Env"), String::from("prod")),
+                    (String::from("Name"), String::from("web-1")),
+                ]),
+            }
+        );
+
+        let details = InstanceDetails {
+            region: Some(String::from("eu-west-1")),
+            account_id: Some(String::from("111111111111")),
+            ..details
+        };
+        assert_eq!(
+            details
+                .details()
+                .into_iter()
+                .map(|(label, value)| format!("{}: {}", label, value))
+                .collect::<Vec<_>>(),
+            vec![
+                "Name: web-1",
+                "Instance ID: i-0abc",
+                "State: running",
+                "Type: t3.micro",
+                "Platform: Linux/UNIX",
+                "AMI: ami-0ubuntu",
+                "Key Pair: ops",
+                "Launched: 2023-11-14T22:13:20Z",
+                "Region: eu-west-1",
+                "AZ: eu-west-1a",
+                "Account: 111111111111",
+                "VPC: vpc-1",
+                "Subnet: subnet-1",
+                "Private IP: 10.0.0.1",
+                "Public IP: 1.2.3.4",
+                "IPv6: 2001:db8::1, 2001:db8::2",
+                "Tags: Env=prod, Name=web-1",
+            ]
+        );
+    }
+
+    #[test]
+    fn platform_falls_back_to_the_platform_field() {
+        use aws_sdk_ec2::types::PlatformValues;
+
+        let instance = Instance::builder()
+            .platform(PlatformValues::Windows)
+            .build();
+        let details = InstanceDetails::from_instance(&instance).unwrap();
+
+        assert_eq!(details.platform.as_deref(), Some("Windows"));
+        assert!(details.details().contains(&("IPv6", String::from("None"))));
+    }
+
+    #[test]
+    fn caches_expire_after_the_ttl() {
+        let fetched = |age: Option<i64>| InstanceSet {
+            fetched_at: age.map(|age| clock::now() - age),
+            ..Default::default()
+        };
+
+        assert!(!fetched(Some(10_000)).is_expired(None));
+        assert!(!fetched(None).is_expired(None));
+        assert!(fetched(None).is_expired(Some(60)));
+        assert!(!fetched(Some(30)).is_expired(Some(60)));
+        assert!(fetched(Some(60)).is_expired(Some(60)));
+    }
+
+    #[test]
+    fn stale_caches_are_served_only_while_revalidating() {
+        let filters = Ec2Filters::new(
+            &[String::from("instance-state-name=running")],
+            &Config::default(),
+        )
+        .unwrap();
+        let cached = |age: i64| InstanceSet {
+            instances: vec![instance("i-1")],
+            filters: filters.clone(),
+            fetched_at: Some(clock::now() - age),
+            stale: false,
+        };
+        let usable = |cached: InstanceSet, filters: &Ec2Filters, serve_stale: bool| {
+            InstanceSet::usable_cache(Some(cached), filters, Some(60), serve_stale)
+                .map(|cached| cached.stale)
+        };
+
+        assert_eq!(usable(cached(10), &filters, false), Some(false));
+        assert_eq!(usable(cached(120), &filters, false), None);
+        assert_eq!(usable(cached(120), &filters, true), Some(true));
+        assert_eq!(usable(cached(10), &Ec2Filters::default(), true), None);
+        assert_eq!(
+            InstanceSet::usable_cache(None, &filters, Some(60), true),
+            None
+        );
+    }
+
+    #[test]
+    fn cache_dirs_never_collide() {
+        let config = Config {
+            accounts: vec![Account {
+                name: String::from("default"),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let profile_dir = |profile: Option<&str>, env: &[(&str, &str)]| {
+            CacheKey::profile_dir(profile, &config, |name| {
+                env.iter()
+                    .find(|(key, _)| *key == name)
+                    .map(|(_, value)| value.to_string())
+            })
+        };
+
+        assert_eq!(profile_dir(Some("default"), &[]), "account-default");
+        assert_eq!(profile_dir(Some("staging"), &[]), "profile-staging");
+        assert_eq!(profile_dir(None, &[]), "profile-default");
+        assert_eq!(profile_dir(None, &[("AWS_PROFILE", "")]), "profile-default");
+        assert_eq!(
+            profile_dir(None, &[("AWS_PROFILE", "staging")]),
+            "profile-staging"
+        );
+        assert_eq!(
+            profile_dir(
+                None,
+                &[("AWS_PROFILE", "staging"), ("AWS_ACCESS_KEY_ID", "AKIA1")]
+            ),
+            format!("env-{}", cache::digest("AKIA1"))
+        );
+        assert_ne!(
+            profile_dir(None, &[("AWS_ACCESS_KEY_ID", "AKIA1")]),
+            profile_dir(None, &[("AWS_ACCESS_KEY_ID", "AKIA2")])
+        );
+        /* profiles only told apart by characters a file name can't hold */
+        assert_eq!(profile_dir(Some("prod_eu"), &[]), "profile-prod_eu");
+        assert_eq!(
+            profile_dir(Some("prod.eu"), &[]),
+            format!("profile-prod_eu-{}", cache::digest("profile-prod.eu"))
+        );
+    }
+
+    #[test]
+    fn regions_key_is_sorted_and_bounded() {
+        let cache_key = |regions: &[&str]| CacheKey {
+            profile: String::from("profile-default"),
+            regions: regions.iter().map(|region| region.to_string()).collect(),
+        };
+
+        assert_eq!(cache_key(&[]).regions_key(), "default");
+        assert_eq!(
+            cache_key(&["us-east-1", "eu-west-1", "us-east-1"]).regions_key(),
+            "eu-west-1_us-east-1"
+        );
+
+        let many = ["ap-southeast-1"; 10]
+            .iter()
+            .enumerate()
+            .map(|(index, region)| format!("{}{}", region, index))
+            .collect::<Vec<_>>();
+        let many = many.iter().map(String::as_str).collect::<Vec<_>>();
+        let key = cache_key(&many).regions_key();
+        assert!(key.starts_with("regions-"));
+        assert_eq!(key, cache_key(&many).regions_key());
+        assert_eq!(key.len(), "regions-".len() + 16);
+    }
+
+    fn write_cached(dir: &Path, profile: &str, regions: &str, ids: &[&str]) -> PathBuf {
+        let path = dir.join(profile).join(format!("{}.json", regions));
+        let instance_set = InstanceSet {
+            instances: ids.iter().map(|id| instance(id)).collect(),
+            fetched_at: Some(clock::now()),
+            ..Default::default()
+        };
+        cache::write(&path, &instance_set).unwrap();
+        path
+    }
+
+    #[test]
+    fn lists_exports_and_clears_cached_sets() {
+        let dir = tempfile::tempdir().unwrap();
+        let instances_dir = dir.path().join("instances");
+        assert!(InstanceSet::cached_in(&instances_dir).unwrap().is_empty());
+
+        let staging = write_cached(&instances_dir, "profile-staging", "default", &["i-3"]);
+        write_cached(&instances_dir, "account-prod", "eu-west-1", &["i-1", "i-2"]);
+        std::fs::write(instances_dir.join("account-prod").join("notes.txt"), "").unwrap();
+
+        let entries = InstanceSet::cached_in(&instances_dir).unwrap();
+        assert_eq!(
+            entries
+                .iter()
+                .map(|entry| (entry.profile.as_str(), entry.instance_set.instances.len()))
+                .collect::<Vec<_>>(),
+            vec![("account-prod", 2), ("profile-staging", 1)]
+        );
+        assert_eq!(entries[1].path, staging);
+
+        let mut exported = Vec::new();
+        CacheEntry::export(entries, &mut exported).unwrap();
+        let exported = serde_json::from_slice::<Vec<InstanceDetails>>(&exported).unwrap();
+        assert_eq!(
+            exported,
+            vec![instance("i-1"), instance("i-2"), instance("i-3")]
+        );
+
+        assert_eq!(InstanceSet::clear_cache_in(&instances_dir).unwrap(), 2);
+        assert!(!instances_dir.exists());
+        assert_eq!(InstanceSet::clear_cache_in(&instances_dir).unwrap(), 0);
+    }
+
+    #[test]
+    fn page_size_is_clamped_to_the_api_bounds() {
+        let page_size = |page_size: Option<i32>| {
+            InstanceSet::page_size(&Config {
+                page_size,
+                ..Default::default()
+            })
+        };
+
+        assert_eq!(page_size(None), 1000);
+        assert_eq!(page_size(Some(50)), 50);
+        assert_eq!(page_size(Some(1)), 5);
+        assert_eq!(page_size(Some(-3)), 5);
+        assert_eq!(page_size(Some(5000)), 1000);
+    }
+
+    #[test]
+    fn truncates_to_max_results() {
+        let mut instances = vec![instance("i-1"), instance("i-2"), instance("i-3")];
+
+        assert!(!InstanceSet::truncate_to(&mut instances, 3));
+        assert_eq!(instances.len(), 3);
+        assert!(InstanceSet::truncate_to(&mut instances, 2));
+        assert_eq!(instances, vec![instance("i-1"), instance("i-2")]);
+        assert!(InstanceSet::truncate_to(&mut instances, 0));
+        assert!(instances.is_empty());
     }
 }
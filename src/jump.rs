@@ -0,0 +1,373 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use aws_config::Region;
+use aws_sdk_ec2::types::Filter;
+
+use crate::cmdgen::{shell_join, CommandGenerator};
+use crate::config::{Config, JumpHop};
+use crate::instance_details::{InstanceDetails, InstanceSet};
+
+const DEFAULT_SSH_PORT: u16 = 22;
+
+/// a hop of a jump chain, resolved to the address ssh connects to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hop {
+    pub host: String,
+    pub user: Option<String>,
+    pub port: Option<u16>,
+    pub key: Option<String>,
+}
+
+impl Hop {
+    /// `bastion` is the instance resolved for a hop selected by tag
+    pub fn resolve(hop: &JumpHop, bastion: Option<&InstanceDetails>) -> Result<Self> {
+        let host = match (&hop.host, &hop.bastion_tag, bastion) {
+            (Some(host), _, _) => host.clone(),
+            (None, Some(_), Some(bastion)) => bastion_address(hop, bastion)?,
+            (None, Some(tag), None) => {
+                return Err(anyhow!(
+                    "No running bastion tagged {} found in the vpc",
+                    tag
+                ))
+            }
+            (None, None, _) => return Err(anyhow!("A jump hop needs a host or a bastion-tag")),
+        };
+
+        Ok(Self {
+            host,
+            user: hop.user.clone(),
+            port: hop.port,
+            key: hop
+                .key
+                .as_ref()
+                .map(|key| shellexpand::tilde(&key.to_string_lossy()).to_string()),
+        })
+    }
+}
+
+/* hops are usually entered from outside the vpc, so the public address is preferred */
+fn bastion_address(hop: &JumpHop, bastion: &InstanceDetails) -> Result<String> {
+    let address = match hop.address_type.as_deref() {
+        Some("public") => bastion.public_ip.clone(),
+        Some("private") => bastion.private_ip.clone(),
+        Some(_) => return Err(anyhow!("Invalid address type")),
+        None => bastion.public_ip.clone().or(bastion.private_ip.clone()),
+    };
+
+    address.ok_or(anyhow!(
+        "The bastion {} has no address to connect to",
+        bastion.instance_id.clone().unwrap_or_default()
+    ))
+}
+
+/// a running instance in the target's vpc carrying the tag, given as `Key=Value` or just `Key`
+pub fn find_bastion<'a>(
+    inventory: &'a [InstanceDetails],
+    tag: &str,
+    target: &InstanceDetails,
+) -> Option<&'a InstanceDetails> {
+    let (key, value) = match tag.split_once('=') {
+        Some((key, value)) => (key, Some(value)),
+        None => (tag, None),
+    };
+
+    inventory.iter().find(|instance| {
+        instance.vpc_id.is_some()
+            && instance.vpc_id == target.vpc_id
+            && instance.instance_id != target.instance_id
+            && instance
+                .state
+                .as_deref()
+                .is_none_or(|state| state == "running")
+            && instance
+                .tags
+                .get(key)
+                .is_some_and(|tagged| value.is_none_or(|value| tagged == value))
+    })
+}
+
+/// looks up the bastions of tagged hops that `CommandGenerator::resolve_bastions` didn't find in
+/// the (possibly filtered) inventory, with a DescribeInstances call of their own. lookups are
+/// done once per account, region, vpc & tag, & a failed one only fails the instances it was
+/// done for
+pub async fn resolve<'a>(
+    command_generators: impl IntoIterator<Item = &'a mut CommandGenerator>,
+    config: &Config,
+) {
+    resolve_with(command_generators, async |key| fetch(key, config).await).await
+}
+
+/* (profile, region, vpc id, tag) */
+type BastionKey = (Option<String>, Option<String>, String, String);
+
+async fn resolve_with<'a>(
+    command_generators: impl IntoIterator<Item = &'a mut CommandGenerator>,
+    mut fetch: impl AsyncFnMut(&BastionKey) -> Result<Vec<InstanceDetails>>,
+) {
+    let mut candidates = HashMap::new();
+    for command_generator in command_generators {
+        for tag in command_generator.unresolved_bastion_tags() {
+            let target = command_generator.instance().clone();
+            let Some(vpc_id) = target.vpc_id.clone() else {
+                continue;
+            };
+            let key = (
+                target.profile.clone(),
+                target.region.clone(),
+                vpc_id,
+                tag.clone(),
+            );
+            if !candidates.contains_key(&key) {
+                let fetched = fetch(&key).await.map_err(|e| format!("{:#}", e));
+                candidates.insert(key.clone(), fetched);
+            }
+
+            let bastion = match &candidates[&key] {
+                Ok(candidates) => Ok(find_bastion(candidates, &tag, &target).cloned()),
+                Err(e) => Err(e.clone()),
+            };
+            command_generator.set_bastion(tag, bastion);
+        }
+    }
+}
+
+/* the running instances of a vpc carrying the tag */
+async fn fetch(
+    (profile, region, vpc_id, tag): &BastionKey,
+    config: &Config,
+) -> Result<Vec<InstanceDetails>> {
+    let mut aws_config = InstanceSet::load_aws_config(profile, config, true).await?;
+    if let Some(region) = region {
+        aws_config = aws_config
+            .to_builder()
+            .region(Region::new(region.clone()))
+            .build();
+    }
+    let client = aws_sdk_ec2::Client::new(&aws_config);
+
+    let tag_filter = match tag.split_once('=') {
+        Some((key, value)) => Filter::builder()
+            .name(format!("tag:{}", key))
+            .values(value)
+            .build(),
+        None => Filter::builder().name("tag-key").values(tag).build(),
+    };
+    let mut pages = client
+        .describe_instances()
+        .filters(tag_filter)
+        .filters(Filter::builder().name("vpc-id").values(vpc_id).build())
+        .filters(
+            Filter::builder()
+                .name("instance-state-name")
+                .values("running")
+                .build(),
+        )
+        .into_paginator()
+        .send();
+
+    let mut instances = Vec::new();
+    while let Some(page) = pages.next().await {
+        instances.extend(page?.reservations().iter().flat_map(|reservation| {
+            InstanceDetails::from_reservation(
+                reservation,
+                region.as_deref().unwrap_or_default(),
+                profile,
+            )
+        }));
+    }
+
+    Ok(instances)
+}
+
+/// the ProxyCommand reaching the target (`%h:%p`) through every hop in order. every hop runs
+/// its own `ssh -W` with its own key, tunnelled through the hops before it by a nested
+/// ProxyCommand, so unlike `-J` no hop has to share the target's identity
+pub fn proxy_command(ssh_binary: &str, hops: &[Hop]) -> Result<String> {
+    match hops.is_empty() {
+        true => Err(anyhow!("A jump chain needs at least one hop")),
+        false => Ok(hop_command(ssh_binary, hops, "%h:%p")),
+    }
+}
+
+/* ssh expands % tokens in a ProxyCommand, & a nested ProxyCommand passes through the expansion
+ * of every ssh above it, so literal values are escaped once per level they're embedded in.
+ * `destination` is already escaped */
+fn hop_command(ssh_binary: &str, hops: &[Hop], destination: &str) -> String {
+    let Some((hop, previous)) = hops.split_last() else {
+        return String::new();
+    };
+
+    let mut args = vec![escape(ssh_binary)];
+    if let Some(key) = &hop.key {
+        args.extend([String::from("-i"), escape(key)]);
+    }
+    if let Some(port) = hop.port {
+        args.extend([String::from("-p"), port.to_string()]);
+    }
+    if !previous.is_empty() {
+        let hop_destination = format!("{}:{}", hop.host, hop.port.unwrap_or(DEFAULT_SSH_PORT));
+        let previous_command = hop_command(ssh_binary, previous, &escape(&hop_destination));
+        args.extend([
+            String::from("-o"),
+            format!("ProxyCommand={}", escape(&previous_command)),
+        ]);
+    }
+    args.extend([String::from("-W"), destination.to_string()]);
+    /* "--" keeps a host starting with "-" from being read as an option */
+    args.push(String::from("--"));
+    args.push(escape(&match &hop.user {
+        Some(user) => format!("{}@{}", user, hop.host),
+        None => hop.host.clone(),
+    }));
+
+    shell_join(&args)
+}
+
+fn escape(value: &str) -> String {
+    value.replace('%', "%%")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::JumpChain;
+    use crate::opts::ConnectOptions;
+    use std::collections::BTreeMap;
+
+    fn hop(host: &str, user: Option<&str>, port: Option<u16>, key: Option<&str>) -> Hop {
+        Hop {
+            host: host.to_string(),
+            user: user.map(String::from),
+            port,
+            key: key.map(String::from),
+        }
+    }
+
+    #[test]
+    fn single_hop_forwards_to_the_target() {
+        assert_eq!(
+            proxy_command("ssh", &[hop("bastion", Some("admin"), None, Some("/k/b"))]).unwrap(),
+            "ssh -i /k/b -W %h:%p -- admin@bastion"
+        );
+    }
+
+    #[test]
+    fn hops_are_nested_with_their_own_keys() {
+        let hops = [
+            hop(
+                "edge.example.com",
+                Some("admin"),
+                Some(2222),
+                Some("/k/edge"),
+            ),
+            hop("10.0.0.5", Some("ops"), None, Some("/k/100%")),
+        ];
+
+        assert_eq!(
+            proxy_command("ssh", &hops).unwrap(),
+            "ssh -i /k/100%% \
+             -o 'ProxyCommand=ssh -i /k/edge -p 2222 -W 10.0.0.5:22 -- admin@edge.example.com' \
+             -W %h:%p -- ops@10.0.0.5"
+        );
+        assert!(proxy_command("ssh", &[]).is_err());
+    }
+
+    #[test]
+    fn finds_a_running_bastion_in_the_target_vpc() {
+        let instance = |id: &str, vpc: &str, state: &str, role: &str| InstanceDetails {
+            instance_id: Some(id.to_string()),
+            vpc_id: Some(vpc.to_string()),
+            state: Some(state.to_string()),
+            tags: BTreeMap::from([(String::from("Role"), role.to_string())]),
+            ..Default::default()
+        };
+        let inventory = vec![
+            instance("i-other-vpc", "vpc-2", "running", "bastion"),
+            instance("i-stopped", "vpc-1", "stopped", "bastion"),
+            instance("i-web", "vpc-1", "running", "web"),
+            instance("i-bastion", "vpc-1", "running", "bastion"),
+        ];
+        let target = instance("i-target", "vpc-1", "running", "app");
+
+        let found = |tag: &str| {
+            find_bastion(&inventory, tag, &target).and_then(|bastion| bastion.instance_id.clone())
+        };
+        assert_eq!(found("Role=bastion").as_deref(), Some("i-bastion"));
+        assert_eq!(found("Role").as_deref(), Some("i-web"));
+        assert_eq!(found("Role=nat"), None);
+    }
+
+    #[test]
+    fn hops_reject_unknown_settings() {
+        assert!(toml::from_str::<JumpHop>("bastion_tag = \"Role=bastion\"").is_err());
+        assert!(toml::from_str::<JumpHop>("bastion-tag = \"Role=bastion\"").is_ok());
+    }
+
+    #[tokio::test]
+    async fn bastions_missing_from_the_inventory_are_looked_up() {
+        let config = Config {
+            default_user: Some(String::from("ec2-user")),
+            jump_chain: Some(String::from("prod")),
+            jump_chains: vec![JumpChain {
+                name: String::from("prod"),
+                hops: vec![JumpHop {
+                    bastion_tag: Some(String::from("Role=bastion")),
+                    ..Default::default()
+                }],
+            }],
+            ..Default::default()
+        };
+        let instance = |id: &str, profile: &str, vpc: &str, role: &str| InstanceDetails {
+            instance_id: Some(id.to_string()),
+            private_ip: Some(format!("10.0.0.{}", id.len())),
+            public_ip: (role == "bastion").then(|| format!("1.2.3.{}", id.len())),
+            profile: Some(profile.to_string()),
+            vpc_id: Some(vpc.to_string()),
+            state: Some(String::from("running")),
+            tags: BTreeMap::from([(String::from("Role"), role.to_string())]),
+            ..Default::default()
+        };
+        let generator = |target: InstanceDetails| {
+            CommandGenerator::new(&ConnectOptions::default(), config.clone(), target).unwrap()
+        };
+        let mut generators = [
+            generator(instance("i-1", "staging", "vpc-1", "app")),
+            generator(instance("i-22", "staging", "vpc-1", "app")),
+            generator(instance("i-333", "staging", "vpc-2", "app")),
+            generator(instance("i-4444", "production", "vpc-1", "app")),
+        ];
+        generators[0].resolve_bastions(&[instance("i-inv", "staging", "vpc-1", "bastion")]);
+
+        let mut lookups = Vec::new();
+        resolve_with(generators.iter_mut(), async |key: &BastionKey| {
+            lookups.push(key.clone());
+            match (key.0.as_deref(), key.2.as_str()) {
+                (Some("production"), _) => Err(anyhow!("UnauthorizedOperation")),
+                (_, "vpc-1") => Ok(vec![instance("i-looked-up", "staging", "vpc-1", "bastion")]),
+                _ => Ok(Vec::new()),
+            }
+        })
+        .await;
+
+        assert_eq!(lookups.len(), 3);
+        let proxy_command = |generator: &CommandGenerator| {
+            generator
+                .args()
+                .map(|args| args[3].clone())
+                .map_err(|e| e.to_string())
+        };
+        assert!(proxy_command(&generators[0])
+            .unwrap()
+            .ends_with("-- 1.2.3.5"));
+        assert!(proxy_command(&generators[1])
+            .unwrap()
+            .ends_with("-- 1.2.3.11"));
+        assert!(proxy_command(&generators[2])
+            .unwrap_err()
+            .contains("No running bastion tagged Role=bastion"));
+        assert!(proxy_command(&generators[3])
+            .unwrap_err()
+            .contains("UnauthorizedOperation"));
+    }
+}
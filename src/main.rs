@@ -1,86 +1,278 @@
 #[macro_use]
 extern crate prettytable;
 
+mod cache;
+mod clock;
 mod cmdgen;
 mod config;
+mod credentials;
+mod eice;
+mod exec;
+mod filters;
+mod fuzzy;
 mod instance_details;
+mod jump;
 mod opts;
+mod rules;
+mod search;
 mod tablegen;
+mod tmux;
 mod ui;
 
-use std::process::Command;
+use std::io::Write;
+use std::os::unix::process::CommandExt;
 
 use anyhow::{anyhow, Result};
-use clap::Parser;
-use instance_details::InstanceSet;
+use instance_details::{InstanceDetails, InstanceSet};
 use opts::Opts;
+use search::Query;
 use tablegen::TableGenerator;
 use ui::Ui;
 
-fn gencmd(
-    opts: opts::ConnectOptions,
-    cli: Opts,
+/* instances matching the search. a single or clear match is taken right away when
+ * `auto_select` allows it, anything else is picked (possibly several at once) in the ui */
+fn select_instances(
+    search: &Option<String>,
+    cli: &Opts,
+    config: &config::Config,
     instance_set: InstanceSet,
-    cmd: &mut Command,
-) -> Result<()> {
-    let filtered_instance_set = instance_set.filter(&opts.search)?;
-    let instance = match filtered_instance_set.is_non_selectable() {
-        true => filtered_instance_set.instances.first().unwrap().clone(),
+    auto_select: bool,
+) -> Result<Vec<InstanceDetails>> {
+    let query = Query::from_search(search, config.match_mode.unwrap_or_default())?;
+    let filtered_instance_set = instance_set.filter(&query)?;
+    let instance_set_stale = filtered_instance_set.stale;
+    let auto_select = auto_select
+        && (filtered_instance_set.is_non_selectable()
+            || filtered_instance_set.has_clear_winner(&query));
+    let instances = match auto_select {
+        true => vec![filtered_instance_set.instances.first().unwrap().clone()],
         false => {
+            let stale = filtered_instance_set.stale;
             let mut ui = Ui::new(
                 filtered_instance_set,
+                query,
+                config.match_mode.unwrap_or_default(),
                 config::Config::read_raw(cli.clone().config)?,
             )?;
 
-            ui.run()?
+            /* refresh while the picker is open & swap the fresh list in once it arrives */
+            if stale {
+                let (sender, receiver) = std::sync::mpsc::channel();
+                let (refresh_cli, refresh_config) = (cli.clone(), config.clone());
+                tokio::spawn(async move {
+                    if let Ok(instance_set) =
+                        InstanceSet::refresh_quietly(&refresh_cli, &refresh_config).await
+                    {
+                        let _ = sender.send(instance_set);
+                    }
+                });
+                ui = ui.with_refresh(receiver);
+            }
+
+            let selected = ui.run();
+            if ui.is_refreshing() {
+                InstanceSet::spawn_background_refresh()?;
+            }
+
+            selected?
         }
     };
 
-    if instance.is_empty() {
-        eprintln!("No instance found");
+    if auto_select && instance_set_stale {
+        InstanceSet::spawn_background_refresh()?;
+    }
+
+    if instances.iter().any(InstanceDetails::is_empty) {
         return Err(anyhow!("No Instance Found"));
     }
 
-    /* run ssh */
+    Ok(instances)
+}
+
+async fn gencmd(
+    opts: opts::ConnectOptions,
+    cli: Opts,
+    instance_set: InstanceSet,
+) -> Result<Vec<cmdgen::CommandGenerator>> {
     let config = config::Config::load(cli.clone().config)?;
-    cmdgen::CommandGenerator::new(&opts, config, instance)?.generate(cmd)?;
+
+    let inventory = instance_set.instances.clone();
+    let mut command_generators = select_instances(&opts.search, &cli, &config, instance_set, true)?
+        .into_iter()
+        .map(|instance| cmdgen::CommandGenerator::new(&opts, config.clone(), instance))
+        .collect::<Result<Vec<_>>>()?;
+    eice::resolve(command_generators.iter_mut(), &config).await;
+    command_generators
+        .iter_mut()
+        .for_each(|command_generator| command_generator.resolve_bastions(&inventory));
+    jump::resolve(command_generators.iter_mut(), &config).await;
+
+    Ok(command_generators)
+}
+
+async fn cache_operation(
+    operation: opts::CacheOperations,
+    cli: &Opts,
+    config: &config::Config,
+) -> Result<()> {
+    match operation {
+        opts::CacheOperations::Status => {
+            let entries = InstanceSet::cached()?;
+            println!("cache directory: {}", cache::cache_dir()?.display());
+            match entries.is_empty() {
+                true => println!("no cached instance lists"),
+                false => TableGenerator::cache_status(&entries, config.cache_ttl).print(),
+            }
+        }
+        opts::CacheOperations::Clear => {
+            let cleared = InstanceSet::clear_cache()?;
+            println!("cleared {} cached instance lists", cleared);
+        }
+        opts::CacheOperations::Refresh(_) => {
+            let instance_set = InstanceSet::refresh(cli, config).await?;
+            println!("cached {} instances", instance_set.instances.len());
+        }
+        opts::CacheOperations::Export => {
+            instance_details::CacheEntry::export(InstanceSet::cached()?, std::io::stdout().lock())?;
+        }
+    }
 
     Ok(())
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let cli = Opts::parse();
-    let instance_set = InstanceSet::fetch(&cli).await?;
+    let cli = Opts::parse_valid();
+    let fetch_config = config::Config::load_or_default(cli.clone().config)?;
+    if std::env::var_os(instance_details::BACKGROUND_REFRESH_ENV).is_some() {
+        InstanceSet::refresh_quietly(&cli, &fetch_config).await?;
+        return Ok(());
+    }
+
+    /* operations that don't need an instance list */
+    match cli.operation.clone() {
+        opts::Operations::Configure => return config::Config::write_default_config(),
+        opts::Operations::Cache(operation) => {
+            return cache_operation(operation, &cli, &fetch_config).await
+        }
+        _ => {}
+    }
+
+    let instance_set = InstanceSet::fetch(&cli, &fetch_config).await?;
     let operation = cli.operation.clone();
     match operation.clone() {
-        opts::Operations::Connect(opts) => {
-            let mut command = Command::new("sh");
-            if gencmd(opts, cli, instance_set, &mut command).is_ok() {
-                command.status()?;
+        opts::Operations::Connect(mut opts) => {
+            /* every tmux window / pane has a terminal of its own */
+            opts.tty = opts.tty || (opts.tmux.is_some() && !opts.no_tty);
+            let config = config::Config::load(cli.clone().config)?;
+            let mut command_generators = gencmd(opts.clone(), cli, instance_set).await?;
+            credentials::resolve(command_generators.iter_mut(), &config).await;
+            let mut command = match (opts.tmux, command_generators.as_slice()) {
+                (Some(layout), _) => {
+                    tmux::Tmux::new(layout, opts.synchronize).command(&command_generators)?
+                }
+                (None, [command_generator]) => command_generator.command()?,
+                (None, _) => {
+                    return Err(anyhow!(
+                        "Only one instance can be connected to at a time. Use --tmux to open each in tmux, or exec to run a command on all of them"
+                    ))
+                }
+            };
+
+            for command_generator in command_generators.iter() {
+                command_generator.prepare()?;
             }
+
+            /* replace blssh with ssh (or tmux), so that its exit status becomes ours */
+            let error = command.exec();
+            return Err(anyhow!(
+                "Unable to run {}: {}",
+                command.get_program().to_string_lossy(),
+                error
+            ));
         }
         opts::Operations::Print(opts) => {
-            let mut command = Command::new("sh");
-            if gencmd(opts, cli, instance_set, &mut command).is_ok() {
-                println!(
-                    "{}",
-                    command
-                        .get_args()
-                        .last()
-                        .unwrap_or_default()
-                        .to_str()
-                        .unwrap_or_default()
-                );
+            let command_generators = gencmd(opts.connect.clone(), cli, instance_set).await?;
+            match opts.connect.tmux {
+                Some(layout) => {
+                    if opts.explain {
+                        for command_generator in command_generators.iter() {
+                            println!("{}", command_generator.explain());
+                        }
+                    }
+                    println!(
+                        "{}",
+                        tmux::Tmux::new(layout, opts.connect.synchronize)
+                            .command_line(&command_generators)?
+                    )
+                }
+                None => {
+                    for command_generator in command_generators {
+                        if opts.explain {
+                            println!("{}", command_generator.explain());
+                        }
+                        println!("{}", command_generator.command_line()?);
+                    }
+                }
+            }
+        }
+        opts::Operations::Exec(opts) => {
+            let config = config::Config::load(cli.clone().config)?;
+            let executor = exec::Executor::new(&opts, config.clone())?;
+            let inventory = instance_set.instances.clone();
+            let instances = match opts.pick {
+                true => select_instances(&opts.connect.search, &cli, &config, instance_set, false)?,
+                false => {
+                    let query = Query::from_search(
+                        &opts.connect.search,
+                        config.match_mode.unwrap_or_default(),
+                    )?;
+                    let filtered_instance_set = instance_set.filter(&query)?;
+                    if filtered_instance_set.stale {
+                        InstanceSet::spawn_background_refresh()?;
+                    }
+                    filtered_instance_set.instances
+                }
+            };
+            if instances.is_empty() {
+                return Err(anyhow!("No Instance Found"));
+            }
+
+            let results = executor.run(instances, &inventory).await?;
+            match opts.json {
+                true => {
+                    let mut stdout = std::io::stdout().lock();
+                    serde_json::to_writer_pretty(&mut stdout, &results)?;
+                    writeln!(stdout)?;
+                }
+                false => TableGenerator::exec_summary(&results).print(),
+            }
+
+            let failed = results
+                .iter()
+                .filter(|result| result.status != exec::ExecStatus::Succeeded)
+                .count();
+            if failed > 0 {
+                return Err(anyhow!(
+                    "Command did not succeed on {} of {} instances",
+                    failed,
+                    results.len()
+                ));
             }
         }
         opts::Operations::List(opts) => {
-            let filtered_instance_set = instance_set.filter(&opts.search)?;
+            let query =
+                Query::from_search(&opts.search, fetch_config.match_mode.unwrap_or_default())?;
+            let filtered_instance_set = instance_set.filter(&query)?;
             TableGenerator::generate(&filtered_instance_set).print();
+            if let Some(age_label) = filtered_instance_set.age_label() {
+                eprintln!("{}", age_label);
+            }
+            if filtered_instance_set.stale {
+                InstanceSet::spawn_background_refresh()?;
+            }
         }
-        opts::Operations::Configure => {
-            config::Config::write_default_config()?;
-        }
+        opts::Operations::Configure | opts::Operations::Cache(_) => {}
     }
 
     Ok(())
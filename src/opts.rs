@@ -1,10 +1,15 @@
 use std::path::PathBuf;
 
-use clap::{Parser, Subcommand};
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand};
 
-#[derive(Parser, Debug, Clone)]
+use crate::cmdgen::Transport;
+use crate::tmux::TmuxLayout;
+
+#[derive(Parser, Debug, Clone, Default)]
 pub struct ConnectOptions {
-    /// Search String to filter instances by
+    /// Search query to filter instances by, e.g. "api -staging state:running OR tag:Env=prod"
+    #[clap(allow_hyphen_values = true)]
     pub search: Option<String>,
 
     /// ssh username
@@ -25,11 +30,93 @@ pub struct ConnectOptions {
     /// jumphost
     #[clap(short, long)]
     pub jumphost: Option<String>,
+
+    /// jump chain (from the config's jump-chains) to connect through
+    #[clap(long)]
+    pub jump_chain: Option<String>,
+
+    /// server side ec2 filter, e.g. instance-state-name=running or tag:Env=prod,staging (repeatable)
+    #[clap(short, long = "filter")]
+    pub filters: Vec<String>,
+
+    /// how to connect: ssh, ssm (session manager), ssh-over-ssm or eice (instance connect endpoint)
+    #[clap(long, value_enum)]
+    pub transport: Option<Transport>,
+
+    /// ec2 instance connect endpoint to tunnel through with --transport eice (default: the one in the instance's vpc)
+    #[clap(long)]
+    pub eice_id: Option<String>,
+
+    /// push a short lived key with ec2 instance connect before connecting
+    #[clap(long)]
+    pub instance_connect: bool,
+
+    /// force pseudo-terminal allocation (default: only when stdin is a terminal)
+    #[clap(short = 't', long = "tty", conflicts_with = "no_tty")]
+    pub tty: bool,
+
+    /// disable pseudo-terminal allocation
+    #[clap(short = 'T', long = "no-tty")]
+    pub no_tty: bool,
+
+    /// open every selected instance in tmux, as a window (default) or a pane each
+    #[clap(long, value_enum, num_args = 0..=1, default_missing_value = "windows")]
+    pub tmux: Option<TmuxLayout>,
+
+    /// type into every tmux pane at once (needs --tmux panes)
+    #[clap(long, requires = "tmux")]
+    pub synchronize: bool,
+
+    /// command to run on the instance instead of an interactive shell, given after `--`
+    #[clap(last = true)]
+    pub command: Vec<String>,
 }
 
 #[derive(Parser, Debug, Clone)]
 pub struct ListOptions {
+    /// Search query to filter instances by, e.g. "api -staging state:running OR tag:Env=prod"
+    #[clap(allow_hyphen_values = true)]
     pub search: Option<String>,
+
+    /// server side ec2 filter, e.g. instance-state-name=running or tag:Env=prod,staging (repeatable)
+    #[clap(short, long = "filter")]
+    pub filters: Vec<String>,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct PrintOptions {
+    #[clap(flatten)]
+    pub connect: ConnectOptions,
+
+    /// show where every connection setting comes from & which config rule matched
+    #[clap(long)]
+    pub explain: bool,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct ExecOptions {
+    #[clap(flatten)]
+    pub connect: ConnectOptions,
+
+    /// how many instances to run the command on at once
+    #[clap(long, default_value_t = crate::exec::DEFAULT_CONCURRENCY)]
+    pub concurrency: usize,
+
+    /// seconds before the command is killed on an instance
+    #[clap(long)]
+    pub timeout: Option<u64>,
+
+    /// choose the instances to run on in the instance picker
+    #[clap(long)]
+    pub pick: bool,
+
+    /// stop at the first instance the command fails on
+    #[clap(long)]
+    pub fail_fast: bool,
+
+    /// print the results (including the output of every instance) as json
+    #[clap(long)]
+    pub json: bool,
 }
 
 #[derive(Debug, Clone, Subcommand)]
@@ -42,10 +129,35 @@ pub enum Operations {
     List(ListOptions),
     /// Print SSH Command
     #[clap(name = "print", alias = "p")]
-    Print(ConnectOptions),
+    Print(PrintOptions),
+    /// run a command (given after --) on every matching ec2 instance
+    #[clap(name = "exec", alias = "x")]
+    Exec(ExecOptions),
     /// generate default config (~/.config/blssh/config.toml)
     #[clap(name = "configure", alias = "cfg")]
     Configure,
+    /// inspect & manage the cached instance lists
+    #[clap(name = "cache", subcommand)]
+    Cache(CacheOperations),
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum CacheOperations {
+    /// show the cache directory & every cached instance list
+    Status,
+    /// remove every cached instance list
+    Clear,
+    /// fetch instances & write them to the cache without connecting
+    Refresh(CacheRefreshOptions),
+    /// print every cached instance as json
+    Export,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct CacheRefreshOptions {
+    /// server side ec2 filter, e.g. instance-state-name=running or tag:Env=prod,staging (repeatable)
+    #[clap(short, long = "filter")]
+    pub filters: Vec<String>,
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -58,7 +170,92 @@ pub struct Opts {
     #[clap(short, long)]
     pub config: Option<PathBuf>,
 
+    /// aws region to discover instances in (repeatable, or "all")
+    #[clap(short, long = "region", global = true)]
+    pub regions: Vec<String>,
+
+    /// named aws profile to discover instances with (repeatable)
+    #[clap(long = "profile", global = true)]
+    pub profiles: Vec<String>,
+
     /// operation to perform (list / connect)
     #[clap(subcommand)]
     pub operation: Operations,
 }
+
+impl Opts {
+    /// parses the command line, also rejecting the option combinations clap can't express
+    pub fn parse_valid() -> Self {
+        let opts = Self::parse();
+        if let Err(message) = opts.validate() {
+            Self::command()
+                .error(ErrorKind::ArgumentConflict, message)
+                .exit();
+        }
+
+        opts
+    }
+
+    fn validate(&self) -> std::result::Result<(), String> {
+        let connect = match &self.operation {
+            Operations::Connect(opts) => opts,
+            Operations::Print(opts) => &opts.connect,
+            Operations::Exec(opts) => &opts.connect,
+            _ => return Ok(()),
+        };
+        /* windows have a pane each, there's nothing to synchronize */
+        if connect.synchronize && connect.tmux != Some(TmuxLayout::Panes) {
+            return Err(String::from(
+                "--synchronize can only be used with --tmux panes",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// server side ec2 filters given to the current operation
+    pub fn filters(&self) -> Vec<String> {
+        match &self.operation {
+            Operations::Connect(opts) => opts.filters.clone(),
+            Operations::Print(opts) => opts.connect.filters.clone(),
+            Operations::List(opts) => opts.filters.clone(),
+            Operations::Exec(opts) => opts.connect.filters.clone(),
+            Operations::Cache(CacheOperations::Refresh(opts)) => opts.filters.clone(),
+            Operations::Configure | Operations::Cache(_) => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Opts {
+        Opts::try_parse_from([&["blssh"], args].concat()).unwrap()
+    }
+
+    #[test]
+    fn regions_and_profiles_are_accepted_after_the_operation() {
+        let opts = parse(&["list", "--region", "eu-west-1", "--profile", "staging"]);
+        assert_eq!(opts.regions, vec!["eu-west-1"]);
+        assert_eq!(opts.profiles, vec!["staging"]);
+
+        let opts = parse(&["--profile", "production", "connect", "-r", "all", "web-1"]);
+        assert_eq!(opts.regions, vec!["all"]);
+        assert_eq!(opts.profiles, vec!["production"]);
+    }
+
+    #[test]
+    fn synchronize_needs_tmux_panes() {
+        assert!(parse(&["connect", "--tmux", "panes", "--synchronize"])
+            .validate()
+            .is_ok());
+        assert!(parse(&["connect", "--tmux", "--synchronize"])
+            .validate()
+            .is_err());
+        assert!(parse(&["print", "--tmux", "windows", "--synchronize"])
+            .validate()
+            .is_err());
+        assert!(Opts::try_parse_from(["blssh", "connect", "--synchronize"]).is_err());
+    }
+}
@@ -0,0 +1,218 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::cmdgen::Transport;
+use crate::config::Config;
+use crate::instance_details::InstanceDetails;
+
+/// settings for the instances matching every condition of `match`, taking precedence over the
+/// global config. only the first matching rule applies
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+/* a misspelled setting would otherwise be silently left to the global config */
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub name: Option<String>,
+    #[serde(rename = "match", default)]
+    pub conditions: RuleConditions,
+    #[serde(rename = "default-user")]
+    pub default_user: Option<String>,
+    #[serde(rename = "private-key")]
+    pub private_key: Option<PathBuf>,
+    pub port: Option<u16>,
+    pub jumphost: Option<String>,
+    #[serde(rename = "jump-chain")]
+    pub jump_chain: Option<String>,
+    #[serde(rename = "address-type")]
+    pub address_type: Option<String>,
+    /// passed to ssh as `-o <option>` each, e.g. "StrictHostKeyChecking=accept-new"
+    #[serde(rename = "ssh-options")]
+    pub ssh_options: Option<Vec<String>>,
+    pub transport: Option<Transport>,
+    #[serde(rename = "eice-id")]
+    pub eice_id: Option<String>,
+}
+
+/// glob patterns (`*` & `?`) an instance has to match. an instance missing a matched attribute
+/// doesn't match
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+/* a misspelled condition would otherwise be ignored, matching every instance */
+#[serde(deny_unknown_fields)]
+pub struct RuleConditions {
+    /// the instance's Name tag
+    pub name: Option<String>,
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    pub ami: Option<String>,
+    pub platform: Option<String>,
+    pub vpc: Option<String>,
+    pub region: Option<String>,
+    /// the account id, or the aws profile / account name the instance was found with
+    pub account: Option<String>,
+}
+
+impl Rule {
+    /// e.g. `rule "ubuntu"`, or `rule #2` for unnamed rules (counting from 1)
+    pub fn label(&self, index: usize) -> String {
+        match &self.name {
+            Some(name) => format!("rule \"{}\"", name),
+            None => format!("rule #{}", index + 1),
+        }
+    }
+
+    pub fn matches(&self, instance: &InstanceDetails) -> bool {
+        let conditions = &self.conditions;
+        let matches = |pattern: &Option<String>, values: &[&Option<String>]| match pattern {
+            Some(pattern) => values.iter().any(|value| {
+                value
+                    .as_deref()
+                    .is_some_and(|value| glob_match(pattern, value))
+            }),
+            None => true,
+        };
+
+        matches(&conditions.name, &[&instance.instance_name])
+            && matches(&conditions.ami, &[&instance.image_id])
+            && matches(&conditions.platform, &[&instance.platform])
+            && matches(&conditions.vpc, &[&instance.vpc_id])
+            && matches(&conditions.region, &[&instance.region])
+            && matches(
+                &conditions.account,
+                &[&instance.account_id, &instance.profile],
+            )
+            && conditions.tags.iter().all(|(key, pattern)| {
+                instance
+                    .tags
+                    .get(key)
+                    .is_some_and(|value| glob_match(pattern, value))
+            })
+    }
+
+    /// the config with this rule's settings in place of the global ones
+    pub fn apply(&self, config: Config) -> Config {
+        /* a rule's jumphost or jump chain replaces both of the global ones, so that a global
+         * chain can't win over a rule's jumphost */
+        let (jumphost, jump_chain) = match self.jumphost.is_some() || self.jump_chain.is_some() {
+            true => (self.jumphost.clone(), self.jump_chain.clone()),
+            false => (config.jumphost.clone(), config.jump_chain.clone()),
+        };
+
+        Config {
+            default_user: self.default_user.clone().or(config.default_user.clone()),
+            private_key: self.private_key.clone().or(config.private_key.clone()),
+            port: self.port.or(config.port),
+            jumphost,
+            jump_chain,
+            address_type: self.address_type.clone().or(config.address_type.clone()),
+            ssh_options: self.ssh_options.clone().or(config.ssh_options.clone()),
+            transport: self.transport.or(config.transport),
+            eice_id: self.eice_id.clone().or(config.eice_id.clone()),
+            ..config
+        }
+    }
+}
+
+/* the whole value has to match, `*` matches any run of characters & `?` a single one */
+fn glob_match(pattern: &str, value: &str) -> bool {
+    let pattern = regex::escape(pattern)
+        .replace("\\*", ".*")
+        .replace("\\?", ".");
+
+    Regex::new(&format!("^{}$", pattern)).is_ok_and(|regex| regex.is_match(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instance() -> InstanceDetails {
+        InstanceDetails {
+            instance_name: Some(String::from("web-prod-1")),
+            image_id: Some(String::from("ami-0ubuntu")),
+            platform: Some(String::from("Linux/UNIX")),
+            vpc_id: Some(String::from("vpc-1")),
+            region: Some(String::from("eu-west-1")),
+            account_id: Some(String::from("111111111111")),
+            profile: Some(String::from("production")),
+            tags: BTreeMap::from([(String::from("Env"), String::from("prod"))]),
+            ..Default::default()
+        }
+    }
+
+    fn rule(conditions: RuleConditions) -> Rule {
+        Rule {
+            conditions,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn matches_every_condition_by_glob() {
+        assert!(rule(RuleConditions::default()).matches(&instance()));
+        assert!(rule(RuleConditions {
+            name: Some(String::from("web-*-?")),
+            tags: BTreeMap::from([(String::from("Env"), String::from("pr*"))]),
+            region: Some(String::from("eu-*")),
+            account: Some(String::from("production")),
+            ..Default::default()
+        })
+        .matches(&instance()));
+
+        assert!(!rule(RuleConditions {
+            name: Some(String::from("web-*")),
+            vpc: Some(String::from("vpc-2")),
+            ..Default::default()
+        })
+        .matches(&instance()));
+        assert!(!rule(RuleConditions {
+            tags: BTreeMap::from([(String::from("Team"), String::from("*"))]),
+            ..Default::default()
+        })
+        .matches(&instance()));
+        assert!(!rule(RuleConditions {
+            name: Some(String::from("web")),
+            ..Default::default()
+        })
+        .matches(&instance()));
+    }
+
+    #[test]
+    fn rule_settings_replace_global_ones() {
+        let global = Config {
+            default_user: Some(String::from("ec2-user")),
+            port: Some(22),
+            jump_chain: Some(String::from("global-chain")),
+            ..Default::default()
+        };
+        let rule = Rule {
+            default_user: Some(String::from("ubuntu")),
+            jumphost: Some(String::from("bastion")),
+            ..Default::default()
+        };
+
+        let config = rule.apply(global);
+        assert_eq!(config.default_user.as_deref(), Some("ubuntu"));
+        assert_eq!(config.port, Some(22));
+        assert_eq!(config.jumphost.as_deref(), Some("bastion"));
+        assert_eq!(config.jump_chain, None);
+    }
+
+    #[test]
+    fn rejects_unknown_conditions() {
+        assert!(toml::from_str::<Rule>("match = { tag = { Env = \"prod\" } }").is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_settings() {
+        assert!(
+            toml::from_str::<Rule>("match = { name = \"web-*\" }\ndefault_user = \"ubuntu\"")
+                .is_err()
+        );
+        assert!(
+            toml::from_str::<Rule>("match = { name = \"web-*\" }\ndefault-user = \"ubuntu\"")
+                .is_ok()
+        );
+    }
+}
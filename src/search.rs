@@ -0,0 +1,644 @@
+use anyhow::{anyhow, Result};
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+
+use crate::fuzzy;
+use crate::instance_details::InstanceDetails;
+
+/*
+ * search query language
+ *
+ *   query  := group ("OR" group)*
+ *   group  := term+                      terms in a group are ANDed together
+ *   term   := ["-"] [field ":"] value    a leading "-" negates the term
+ *   value  := word | "quoted phrase" | /regex/
+ *
+ * fields: name, id, ip, az, type, state, region, account, vpc, subnet, ami, platform & tag.
+ * tag terms take the form tag:Key (exists), tag:Key=value (equals) or tag:Key~regex.
+ * unprefixed terms match any searchable attribute. text matching is case insensitive.
+ * depending on the match mode, unquoted words match as substrings, fuzzily or as regexes.
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Any,
+    Name,
+    Id,
+    Ip,
+    Az,
+    Type,
+    State,
+    Region,
+    Account,
+    Vpc,
+    Subnet,
+    Ami,
+    Platform,
+}
+
+impl Field {
+    fn from_prefix(prefix: &str) -> Option<Self> {
+        match prefix {
+            "name" => Some(Field::Name),
+            "id" => Some(Field::Id),
+            "ip" => Some(Field::Ip),
+            "az" => Some(Field::Az),
+            "type" => Some(Field::Type),
+            "state" => Some(Field::State),
+            "region" => Some(Field::Region),
+            "account" => Some(Field::Account),
+            "vpc" => Some(Field::Vpc),
+            "subnet" => Some(Field::Subnet),
+            "ami" => Some(Field::Ami),
+            "platform" => Some(Field::Platform),
+            _ => None,
+        }
+    }
+
+    pub fn values(&self, instance: &InstanceDetails) -> Vec<String> {
+        let values = match self {
+            Field::Any => return instance.searchable_fields(),
+            Field::Name => vec![instance.instance_name.clone()],
+            Field::Id => vec![instance.instance_id.clone()],
+            Field::Ip => {
+                let mut ips = vec![instance.private_ip.clone(), instance.public_ip.clone()];
+                ips.extend(instance.ipv6_addresses.iter().cloned().map(Some));
+                ips
+            }
+            Field::Az => vec![instance.availability_zone.clone()],
+            Field::Type => vec![instance.instance_type.clone()],
+            Field::State => vec![instance.state.clone()],
+            Field::Region => vec![instance.region.clone()],
+            Field::Account => vec![instance.profile.clone(), instance.account_id.clone()],
+            Field::Vpc => vec![instance.vpc_id.clone()],
+            Field::Subnet => vec![instance.subnet_id.clone()],
+            Field::Ami => vec![instance.image_id.clone()],
+            Field::Platform => vec![instance.platform.clone()],
+        };
+
+        values.into_iter().flatten().collect()
+    }
+}
+
+/// how unquoted search words are matched, the `match-mode` config key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchMode {
+    #[default]
+    Substring,
+    Fuzzy,
+    Regex,
+}
+
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    /// case insensitive substring, stored lowercased
+    Text(String),
+    /// fzf style subsequence, stored lowercased
+    Fuzzy(String),
+    Regex(Regex),
+}
+
+impl Pattern {
+    /// match score (only fuzzy patterns rank matches) & the char positions matched in `value`
+    pub fn score(&self, value: &str) -> Option<(i64, Vec<usize>)> {
+        match self {
+            Pattern::Text(text) => {
+                let lowercase = value.to_lowercase();
+                let start = lowercase.find(text.as_str())?;
+                let start = lowercase[..start].chars().count();
+                Some((0, (start..start + text.chars().count()).collect()))
+            }
+            Pattern::Fuzzy(pattern) => fuzzy::score(pattern, value),
+            Pattern::Regex(regex) => {
+                let found = regex.find(value)?;
+                let start = value[..found.start()].chars().count();
+                Some((0, (start..start + found.as_str().chars().count()).collect()))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Term {
+    Field(Field, Pattern),
+    /// `tag:Key`
+    TagExists(String),
+    /// `tag:Key=value`
+    TagEquals(String, String),
+    /// `tag:Key~regex`
+    TagMatches(String, Regex),
+}
+
+impl Term {
+    /// the best score of any of the term's fields, None if the term doesn't match
+    pub fn score(&self, instance: &InstanceDetails) -> Option<i64> {
+        match self {
+            Term::Field(field, pattern) => field
+                .values(instance)
+                .iter()
+                .filter_map(|value| pattern.score(value))
+                .map(|(score, _)| score)
+                .max(),
+            Term::TagExists(key) => instance.tags.contains_key(key).then_some(0),
+            Term::TagEquals(key, value) => (instance.tags.get(key) == Some(value)).then_some(0),
+            Term::TagMatches(key, regex) => instance
+                .tags
+                .get(key)
+                .is_some_and(|value| regex.is_match(value))
+                .then_some(0),
+        }
+    }
+
+    /* char positions in the instance name matched by the term */
+    fn name_positions(&self, instance: &InstanceDetails) -> Vec<usize> {
+        match (self, instance.instance_name.as_deref()) {
+            (Term::Field(Field::Any | Field::Name, pattern), Some(name)) => pattern
+                .score(name)
+                .map(|(_, positions)| positions)
+                .unwrap_or_default(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// a parsed search query, groups of (possibly negated) terms separated by OR
+#[derive(Debug, Clone, Default)]
+pub struct Query {
+    groups: Vec<Vec<(bool, Term)>>,
+}
+
+impl Query {
+    pub fn parse_with(input: &str, mode: MatchMode) -> Result<Self> {
+        Parser::new(input, mode).parse()
+    }
+
+    /// parses an optional search argument, no search at all matches every instance
+    pub fn from_search(search: &Option<String>, mode: MatchMode) -> Result<Self> {
+        match search {
+            Some(search) => Self::parse_with(search, mode),
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// the score of the best matching OR group, None if no group matches. an empty query
+    /// matches every instance
+    pub fn score(&self, instance: &InstanceDetails) -> Option<i64> {
+        if self.groups.is_empty() {
+            return Some(0);
+        }
+
+        self.groups
+            .iter()
+            .filter_map(|group| {
+                group
+                    .iter()
+                    .map(|(negated, term)| match (negated, term.score(instance)) {
+                        (false, Some(score)) => Some(score),
+                        (true, None) => Some(0),
+                        _ => None,
+                    })
+                    .sum::<Option<i64>>()
+            })
+            .max()
+    }
+
+    /// char positions in the instance name matched by any non negated term, for highlighting
+    pub fn highlights(&self, instance: &InstanceDetails) -> Vec<usize> {
+        let mut positions = self
+            .groups
+            .iter()
+            .flatten()
+            .filter(|(negated, _)| !negated)
+            .flat_map(|(_, term)| term.name_positions(instance))
+            .collect::<Vec<_>>();
+
+        positions.sort_unstable();
+        positions.dedup();
+        positions
+    }
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    chars: Vec<(usize, char)>,
+    position: usize,
+    mode: MatchMode,
+}
+
+impl<'a> Parser<'a> {
+    fn new(input: &'a str, mode: MatchMode) -> Self {
+        Self {
+            input,
+            chars: input.char_indices().collect(),
+            position: 0,
+            mode,
+        }
+    }
+
+    fn parse(mut self) -> Result<Query> {
+        let mut groups = Vec::new();
+        let mut group = Vec::new();
+
+        loop {
+            self.skip_whitespace();
+            let start = self.offset();
+            match self.peek() {
+                None => break,
+                Some(_) if self.at_or() => {
+                    if group.is_empty() {
+                        return Err(self.error(start, "OR must come between two terms"));
+                    }
+                    self.position += 2;
+                    groups.push(std::mem::take(&mut group));
+                }
+                Some(_) => group.push(self.term()?),
+            }
+        }
+
+        if group.is_empty() && !groups.is_empty() {
+            return Err(self.error(self.input.len(), "OR must come between two terms"));
+        }
+        if !group.is_empty() {
+            groups.push(group);
+        }
+
+        Ok(Query { groups })
+    }
+
+    fn term(&mut self) -> Result<(bool, Term)> {
+        let negated = self.peek() == Some('-')
+            && self
+                .chars
+                .get(self.position + 1)
+                .is_some_and(|(_, c)| !c.is_whitespace());
+        if negated {
+            self.position += 1;
+        }
+
+        let start = self.offset();
+        let prefix = self.field_prefix();
+        let term = match prefix.as_deref() {
+            None => Term::Field(Field::Any, self.pattern()?),
+            Some("tag") => self.tag_term(start)?,
+            Some(prefix) => match Field::from_prefix(prefix) {
+                Some(field) => Term::Field(field, self.pattern()?),
+                None => {
+                    return Err(self.error(
+                        start,
+                        &format!(
+                            "unknown field \"{}\" (expected one of name, id, ip, az, type, state, region, account, vpc, subnet, ami, platform or tag, quote the term to search for it literally)",
+                            prefix
+                        ),
+                    ))
+                }
+            },
+        };
+
+        Ok((negated, term))
+    }
+
+    /* a run of ascii letters directly followed by ':' */
+    fn field_prefix(&mut self) -> Option<String> {
+        let mut end = self.position;
+        while self
+            .chars
+            .get(end)
+            .is_some_and(|(_, c)| c.is_ascii_alphabetic())
+        {
+            end += 1;
+        }
+
+        match end > self.position && self.chars.get(end).map(|(_, c)| *c) == Some(':') {
+            true => {
+                let prefix = self.chars[self.position..end]
+                    .iter()
+                    .map(|(_, c)| c)
+                    .collect::<String>();
+                self.position = end + 1;
+                Some(prefix)
+            }
+            false => None,
+        }
+    }
+
+    fn tag_term(&mut self, start: usize) -> Result<Term> {
+        let key = match self.peek() {
+            Some('"') => self.quoted()?,
+            _ => self.take_while(|c| !c.is_whitespace() && c != '=' && c != '~'),
+        };
+        if key.is_empty() {
+            return Err(self.error(
+                start,
+                "missing tag key, expected tag:Key, tag:Key=value or tag:Key~regex",
+            ));
+        }
+
+        match self.peek() {
+            Some('=') => {
+                self.position += 1;
+                Ok(Term::TagEquals(key, self.text()?))
+            }
+            Some('~') => {
+                self.position += 1;
+                let regex_start = self.offset();
+                let regex = match self.peek() {
+                    Some('/') => self.regex_source()?,
+                    _ => self.text()?,
+                };
+                Ok(Term::TagMatches(key, self.compile(&regex, regex_start)?))
+            }
+            _ => Ok(Term::TagExists(key)),
+        }
+    }
+
+    fn pattern(&mut self) -> Result<Pattern> {
+        let start = self.offset();
+        match self.peek() {
+            Some('/') => {
+                let source = self.regex_source()?;
+                Ok(Pattern::Regex(self.compile(&source, start)?))
+            }
+            /* quoted phrases always match literally */
+            Some('"') => Ok(Pattern::Text(self.quoted()?.to_lowercase())),
+            _ => {
+                let text = self.text()?;
+                match (text.is_empty(), self.mode) {
+                    (true, _) => Err(self.error(start, "expected a search term")),
+                    (false, MatchMode::Substring) => Ok(Pattern::Text(text.to_lowercase())),
+                    (false, MatchMode::Fuzzy) => Ok(Pattern::Fuzzy(text.to_lowercase())),
+                    (false, MatchMode::Regex) => Ok(Pattern::Regex(self.compile(&text, start)?)),
+                }
+            }
+        }
+    }
+
+    /* a quoted phrase or a bare word */
+    fn text(&mut self) -> Result<String> {
+        match self.peek() {
+            Some('"') => self.quoted(),
+            _ => Ok(self.take_while(|c| !c.is_whitespace())),
+        }
+    }
+
+    fn quoted(&mut self) -> Result<String> {
+        let start = self.offset();
+        self.position += 1;
+        let phrase = self.take_while(|c| c != '"');
+        match self.peek() {
+            Some('"') => {
+                self.position += 1;
+                Ok(phrase)
+            }
+            _ => Err(self.error(start, "unterminated quote")),
+        }
+    }
+
+    /* /regex/, a backslash escapes the closing slash */
+    fn regex_source(&mut self) -> Result<String> {
+        let start = self.offset();
+        self.position += 1;
+        let mut source = String::new();
+        loop {
+            match self.peek() {
+                None => return Err(self.error(start, "unterminated regex, expected a closing /")),
+                Some('/') => {
+                    self.position += 1;
+                    return Ok(source);
+                }
+                Some('\\') if self.chars.get(self.position + 1).map(|(_, c)| *c) == Some('/') => {
+                    source.push('/');
+                    self.position += 2;
+                }
+                Some(c) => {
+                    source.push(c);
+                    self.position += 1;
+                }
+            }
+        }
+    }
+
+    fn compile(&self, source: &str, start: usize) -> Result<Regex> {
+        RegexBuilder::new(source)
+            .case_insensitive(true)
+            .build()
+            .map_err(|e| self.error(start, &format!("invalid regex: {}", e)))
+    }
+
+    fn at_or(&self) -> bool {
+        let word = self.chars[self.position..]
+            .iter()
+            .take_while(|(_, c)| !c.is_whitespace())
+            .map(|(_, c)| c)
+            .collect::<String>();
+
+        word == "OR"
+    }
+
+    fn take_while(&mut self, predicate: impl Fn(char) -> bool) -> String {
+        let mut taken = String::new();
+        while let Some(c) = self.peek().filter(|c| predicate(*c)) {
+            taken.push(c);
+            self.position += 1;
+        }
+
+        taken
+    }
+
+    fn skip_whitespace(&mut self) {
+        self.take_while(char::is_whitespace);
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).map(|(_, c)| *c)
+    }
+
+    /* byte offset of the current position, used to point at errors */
+    fn offset(&self) -> usize {
+        self.chars
+            .get(self.position)
+            .map(|(offset, _)| *offset)
+            .unwrap_or(self.input.len())
+    }
+
+    fn error(&self, offset: usize, message: &str) -> anyhow::Error {
+        let column = self.input[..offset].chars().count();
+        anyhow!(
+            "Invalid search query: {}\n  {}\n  {}^",
+            message,
+            self.input,
+            " ".repeat(column)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn instance(name: &str, env: &str, state: &str) -> InstanceDetails {
+        InstanceDetails {
+            instance_name: Some(String::from(name)),
+            instance_id: Some(format!("i-{}", name)),
+            private_ip: Some(String::from("10.0.0.12")),
+            state: Some(String::from(state)),
+            availability_zone: Some(String::from("us-east-1a")),
+            instance_type: Some(String::from("t3.large")),
+            tags: BTreeMap::from([
+                (String::from("Env"), String::from(env)),
+                (String::from("Service"), String::from("api-gateway")),
+            ]),
+            ..Default::default()
+        }
+    }
+
+    fn parse(query: &str) -> Result<Query> {
+        Query::parse_with(query, MatchMode::Substring)
+    }
+
+    fn matches(query: &str, instance: &InstanceDetails) -> bool {
+        parse(query).unwrap().score(instance).is_some()
+    }
+
+    #[test]
+    fn empty_query_matches_everything() {
+        assert!(matches("", &instance("api", "prod", "running")));
+        assert!(matches("   ", &instance("api", "prod", "running")));
+    }
+
+    #[test]
+    fn terms_are_anded_and_case_insensitive() {
+        let api = instance("API-prod-1", "prod", "running");
+        assert!(matches("api prod", &api));
+        assert!(matches("api t3.large", &api));
+        assert!(!matches("api staging", &api));
+    }
+
+    #[test]
+    fn or_separates_groups() {
+        let api = instance("api-prod-1", "prod", "running");
+        assert!(matches("web OR api", &api));
+        assert!(matches("web staging OR api prod", &api));
+        assert!(!matches("web OR worker", &api));
+        /* lowercase or is just text */
+        assert!(!matches("web or api", &api));
+    }
+
+    #[test]
+    fn negated_terms_exclude_matches() {
+        let api = instance("api-prod-1", "prod", "running");
+        assert!(matches("api -staging", &api));
+        assert!(!matches("api -prod", &api));
+        assert!(!matches("-tag:Env=prod", &api));
+        assert!(matches("-state:stopped", &api));
+    }
+
+    #[test]
+    fn field_prefixes_restrict_matching() {
+        let api = instance("api-prod-1", "prod", "running");
+        assert!(matches("name:api", &api));
+        assert!(!matches("name:running", &api));
+        assert!(matches("state:running", &api));
+        assert!(matches("id:i-api", &api));
+        assert!(matches("ip:10.0.0", &api));
+        assert!(matches("az:us-east-1a", &api));
+        assert!(matches("type:t3", &api));
+    }
+
+    #[test]
+    fn tag_terms_match_keys_values_and_regexes() {
+        let api = instance("api-prod-1", "prod", "running");
+        assert!(matches("tag:Env", &api));
+        assert!(!matches("tag:Team", &api));
+        assert!(matches("tag:Env=prod", &api));
+        assert!(!matches("tag:Env=pro", &api));
+        assert!(matches("tag:Service~^api-", &api));
+        assert!(matches("tag:Service~/^api-/", &api));
+        assert!(!matches("tag:Service~^web-", &api));
+    }
+
+    #[test]
+    fn quoted_phrases_keep_whitespace() {
+        let spaced = instance("api prod 1", "my env", "running");
+        assert!(matches("\"api prod\"", &spaced));
+        assert!(!matches("\"api 1\"", &spaced));
+        assert!(matches("tag:Env=\"my env\"", &spaced));
+    }
+
+    #[test]
+    fn regexes_match_fields() {
+        let api = instance("api-prod-12", "prod", "running");
+        assert!(matches("/^api-prod-\\d+$/", &api));
+        assert!(matches("name:/prod-1[0-9]/", &api));
+        assert!(!matches("name:/^prod/", &api));
+    }
+
+    #[test]
+    fn malformed_queries_are_rejected_with_position() {
+        let error = parse("name:\"api").unwrap_err().to_string();
+        assert!(error.contains("unterminated quote"));
+        assert!(error.ends_with("\n       ^"));
+
+        assert!(parse("/api")
+            .unwrap_err()
+            .to_string()
+            .contains("unterminated regex"));
+        assert!(parse("name:/(/")
+            .unwrap_err()
+            .to_string()
+            .contains("invalid regex"));
+        assert!(parse("foo:bar")
+            .unwrap_err()
+            .to_string()
+            .contains("unknown field \"foo\""));
+        assert!(parse("OR api").is_err());
+        assert!(parse("api OR").is_err());
+        assert!(parse("api OR OR web").is_err());
+        assert!(parse("tag:").is_err());
+        assert!(parse("tag:=prod").is_err());
+        assert!(parse("name:").is_err());
+    }
+
+    #[test]
+    fn fuzzy_mode_ranks_contiguous_matches_first() {
+        let query = Query::parse_with("api-prod", MatchMode::Fuzzy).unwrap();
+        let strong = instance("service-api-production-3", "prod", "running");
+        let weak = instance("api-gateway-proxy-dev", "dev", "running");
+        let unrelated = instance("worker", "prod", "running");
+
+        assert!(query.score(&strong).unwrap() > query.score(&weak).unwrap());
+        assert!(query.score(&unrelated).is_none());
+        assert_eq!(query.highlights(&strong), (8..16).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn fuzzy_mode_keeps_quoted_phrases_literal() {
+        let query = Query::parse_with("\"apiprod\"", MatchMode::Fuzzy).unwrap();
+        assert!(query
+            .score(&instance("api-prod", "prod", "running"))
+            .is_none());
+    }
+
+    #[test]
+    fn regex_mode_treats_words_as_regexes() {
+        let query = Query::parse_with("^api-prod-\\d$", MatchMode::Regex).unwrap();
+        assert!(query
+            .score(&instance("api-prod-1", "prod", "running"))
+            .is_some());
+        assert!(query
+            .score(&instance("api-prod-12", "prod", "running"))
+            .is_none());
+        assert!(Query::parse_with("api(", MatchMode::Regex).is_err());
+    }
+
+    #[test]
+    fn non_alphabetic_prefixes_are_plain_text() {
+        let ipv6 = InstanceDetails {
+            ipv6_addresses: vec![String::from("fe80::1")],
+            ..Default::default()
+        };
+        assert!(matches("fe80::1", &ipv6));
+        assert!(matches("ip:fe80::1", &ipv6));
+    }
+}
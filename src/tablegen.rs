@@ -1,4 +1,8 @@
-use crate::instance_details::InstanceSet;
+use std::collections::BTreeMap;
+
+use crate::clock;
+use crate::exec::ExecResult;
+use crate::instance_details::{CacheEntry, InstanceSet};
 use prettytable::{format, Cell, Row, Table};
 
 pub struct TableGenerator {
@@ -11,7 +15,18 @@ impl TableGenerator {
         table.set_format(*format::consts::FORMAT_NO_LINESEP_WITH_TITLE);
 
         /* Header Row */
-        table.set_titles(row!["Name", "Public IP", "Private IP", "Instance ID"]);
+        table.set_titles(row![
+            "Name",
+            "State",
+            "Type",
+            "Public IP",
+            "Private IP",
+            "Instance ID",
+            "Region",
+            "AZ",
+            "Account",
+            "Launched"
+        ]);
 
         /* Data */
         instance_set
@@ -22,16 +37,181 @@ impl TableGenerator {
                 let instance_clone = instance.clone();
                 table.add_row(Row::new(vec![
                     Cell::new(&instance_clone.instance_name.unwrap_or("".to_string())),
+                    Cell::new(&instance_clone.state.unwrap_or("".to_string())),
+                    Cell::new(&instance_clone.instance_type.unwrap_or("".to_string())),
                     Cell::new(&instance_clone.public_ip.unwrap_or("".to_string())),
                     Cell::new(&instance_clone.private_ip.unwrap_or("".to_string())),
                     Cell::new(&instance_clone.instance_id.unwrap_or("".to_string())),
+                    Cell::new(&instance_clone.region.unwrap_or("".to_string())),
+                    Cell::new(&instance_clone.availability_zone.unwrap_or("".to_string())),
+                    Cell::new(&instance.account_label().unwrap_or("".to_string())),
+                    Cell::new(&instance_clone.launch_time.unwrap_or("".to_string())),
                 ]));
             });
 
         TableGenerator { table }
     }
 
+    /// one row per cached instance list, with instance counts broken down by region
+    pub fn cache_status(entries: &[CacheEntry], cache_ttl: Option<u64>) -> Self {
+        let mut table = Table::new();
+        table.set_format(*format::consts::FORMAT_NO_LINESEP_WITH_TITLE);
+
+        /* Header Row */
+        table.set_titles(row![
+            "Account",
+            "Regions",
+            "Instances",
+            "Fetched",
+            "Filters",
+            "File"
+        ]);
+
+        /* Data */
+        entries.iter().for_each(|entry| {
+            let mut regions = BTreeMap::<String, usize>::new();
+            entry.instance_set.instances.iter().for_each(|instance| {
+                *regions
+                    .entry(instance.region.clone().unwrap_or("unknown".to_string()))
+                    .or_default() += 1
+            });
+
+            let fetched = match entry.instance_set.age() {
+                Some(age) => format!(
+                    "{} ago{}",
+                    clock::format_age(age),
+                    match entry.instance_set.is_expired(cache_ttl) {
+                        true => " (stale)",
+                        false => "",
+                    }
+                ),
+                None => "unknown".to_string(),
+            };
+
+            table.add_row(Row::new(vec![
+                Cell::new(&entry.profile),
+                Cell::new(
+                    &regions
+                        .iter()
+                        .map(|(region, count)| format!("{} ({})", region, count))
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                ),
+                Cell::new(&entry.instance_set.instances.len().to_string()),
+                Cell::new(&fetched),
+                Cell::new(&entry.instance_set.filters.to_string()),
+                Cell::new(
+                    &entry
+                        .path
+                        .file_name()
+                        .map(|name| name.to_string_lossy().to_string())
+                        .unwrap_or_default(),
+                ),
+            ]));
+        });
+
+        TableGenerator { table }
+    }
+
+    /// one row per instance an exec command ran (or was meant to run) on
+    pub fn exec_summary(results: &[ExecResult]) -> Self {
+        let mut table = Table::new();
+        table.set_format(*format::consts::FORMAT_NO_LINESEP_WITH_TITLE);
+
+        /* Header Row */
+        table.set_titles(row![
+            "Name",
+            "Instance ID",
+            "Status",
+            "Exit Code",
+            "Duration"
+        ]);
+
+        /* Data */
+        results.iter().for_each(|result| {
+            table.add_row(Row::new(vec![
+                Cell::new(&result.name),
+                Cell::new(&result.instance_id.clone().unwrap_or("".to_string())),
+                Cell::new(&match result.error.clone() {
+                    Some(error) => format!("{}: {}", result.status.label(), error),
+                    None => result.status.label().to_string(),
+                }),
+                Cell::new(
+                    &result
+                        .exit_code
+                        .map(|exit_code| exit_code.to_string())
+                        .unwrap_or("".to_string()),
+                ),
+                Cell::new(&format!("{:.1}s", result.duration_ms as f64 / 1000.0)),
+            ]));
+        });
+
+        TableGenerator { table }
+    }
+
     pub fn print(&self) {
         self.table.printstd();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instance_details::InstanceDetails;
+    use std::path::PathBuf;
+
+    fn entry(profile: &str, age: i64, regions: &[&str]) -> CacheEntry {
+        CacheEntry {
+            profile: profile.to_string(),
+            path: PathBuf::from(format!("/cache/instances/{}/default.json", profile)),
+            instance_set: InstanceSet {
+                instances: regions
+                    .iter()
+                    .map(|region| InstanceDetails {
+                        region: Some(region.to_string()),
+                        ..Default::default()
+                    })
+                    .collect(),
+                fetched_at: Some(clock::now() - age),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn cache_status_counts_instances_per_region() {
+        let entries = [
+            entry(
+                "account-prod",
+                150,
+                &["us-east-1", "eu-west-1", "eu-west-1"],
+            ),
+            entry("profile-staging", 7200, &[]),
+        ];
+        let table = TableGenerator::cache_status(&entries, Some(3600)).table;
+        let cells = |row: usize| {
+            table
+                .get_row(row)
+                .unwrap()
+                .iter()
+                .map(|cell| cell.get_content())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(table.len(), 2);
+        assert_eq!(
+            cells(0),
+            vec![
+                "account-prod",
+                "eu-west-1 (2), us-east-1 (1)",
+                "3",
+                "2m ago",
+                "",
+                "default.json"
+            ]
+        );
+        assert_eq!(cells(1)[0], "profile-staging");
+        assert_eq!(cells(1)[2], "0");
+        assert_eq!(cells(1)[3], "2h ago (stale)");
+    }
+}
@@ -0,0 +1,326 @@
+use std::process::Command;
+
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+
+use crate::cmdgen::{shell_quote, CommandGenerator};
+
+pub const TMUX_BINARY: &str = "tmux";
+/* name of the window holding every pane in panes mode */
+pub const PANES_WINDOW_NAME: &str = "blssh";
+
+/// how instances opened in tmux are laid out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum TmuxLayout {
+    /// a window per instance
+    #[default]
+    Windows,
+    /// a tiled pane per instance, all in one window
+    Panes,
+}
+
+/* opens an ssh session per instance in tmux: in a new session, or in the current one when run
+ * inside tmux. everything is done by a single tmux invocation chaining commands with ";" &
+ * every ssh argv is passed after "--", so no value is ever interpreted by a shell */
+pub struct Tmux {
+    layout: TmuxLayout,
+    synchronize: bool,
+    inside_tmux: bool,
+}
+
+impl Tmux {
+    pub fn new(layout: TmuxLayout, synchronize: bool) -> Self {
+        Self {
+            layout,
+            synchronize,
+            inside_tmux: std::env::var_os("TMUX").is_some(),
+        }
+    }
+
+    /// the tmux argv, program first
+    pub fn args(&self, command_generators: &[CommandGenerator]) -> Result<Vec<String>> {
+        if command_generators.is_empty() {
+            return Err(anyhow!("No Instance Found"));
+        }
+
+        let mut args = vec![String::from(TMUX_BINARY)];
+        for (index, command_generator) in command_generators.iter().enumerate() {
+            let instance = command_generator.instance();
+            let name = escape_title(
+                &instance
+                    .instance_name
+                    .clone()
+                    .or(instance.instance_id.clone())
+                    .unwrap_or("None".to_string()),
+            );
+            let window_name = match self.layout {
+                TmuxLayout::Windows => name.clone(),
+                TmuxLayout::Panes => String::from(PANES_WINDOW_NAME),
+            };
+
+            if index > 0 {
+                args.push(String::from(";"));
+            }
+            let create = match (index, self.layout, self.inside_tmux) {
+                (0, _, false) => vec!["new-session", "-n", &window_name],
+                (0, _, true) | (_, TmuxLayout::Windows, _) => {
+                    vec!["new-window", "-n", &window_name]
+                }
+                (_, TmuxLayout::Panes, _) => vec!["split-window"],
+            };
+            args.extend(create.into_iter().map(String::from));
+            /* the tmux server doesn't pass our environment on to its windows */
+            for (key, value) in command_generator.aws_env()? {
+                args.extend([
+                    String::from("-e"),
+                    escape_argument(&format!("{}={}", key, value)),
+                ]);
+            }
+            args.push(String::from("--"));
+            args.extend(
+                command_generator
+                    .args()?
+                    .iter()
+                    .map(|arg| escape_argument(arg)),
+            );
+
+            /* re-tile after every split, so that there's always room for the next pane */
+            if self.layout == TmuxLayout::Panes {
+                args.extend(
+                    [";", "select-layout", "tiled", ";", "select-pane", "-T"].map(String::from),
+                );
+                args.push(name);
+            }
+        }
+
+        if self.layout == TmuxLayout::Panes && self.synchronize {
+            args.extend([";", "set-window-option", "synchronize-panes", "on"].map(String::from));
+        }
+
+        Ok(args)
+    }
+
+    pub fn command(&self, command_generators: &[CommandGenerator]) -> Result<Command> {
+        let args = self.args(command_generators)?;
+        let mut cmd = Command::new(&args[0]);
+        cmd.args(&args[1..]);
+
+        Ok(cmd)
+    }
+
+    /// the tmux command as a line that can be pasted into a posix shell, preceded by the key
+    /// pushes when using instance connect
+    pub fn command_line(&self, command_generators: &[CommandGenerator]) -> Result<String> {
+        let mut lines = Vec::new();
+        for command_generator in command_generators {
+            lines.extend(command_generator.prepare_line()?);
+        }
+        lines.push(
+            self.args(command_generators)?
+                .iter()
+                .map(|arg| shell_quote(arg))
+                .collect::<Vec<_>>()
+                .join(" "),
+        );
+
+        Ok(lines.join(" && "))
+    }
+}
+
+/* tmux splits its argv into commands at every argument ending in ";", unless that's escaped
+ * as "\;" */
+fn escape_argument(arg: &str) -> String {
+    match arg.strip_suffix(';') {
+        Some(arg) => format!("{}\\;", arg),
+        None => arg.to_string(),
+    }
+}
+
+/* window & pane titles are expanded as formats too, where "#(...)" runs a command */
+fn escape_title(title: &str) -> String {
+    escape_argument(&title.replace('#', "##"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::instance_details::InstanceDetails;
+    use crate::opts::ConnectOptions;
+
+    fn command_generators(names: &[&str]) -> Vec<CommandGenerator> {
+        let config = Config {
+            default_user: Some(String::from("ec2-user")),
+            ..Default::default()
+        };
+        let opts = ConnectOptions {
+            tty: true,
+            ..Default::default()
+        };
+
+        names
+            .iter()
+            .enumerate()
+            .map(|(index, name)| {
+                let instance = InstanceDetails {
+                    instance_name: Some(name.to_string()),
+                    private_ip: Some(format!("10.0.0.{}", index + 1)),
+                    ..Default::default()
+                };
+                CommandGenerator::new(&opts, config.clone(), instance).unwrap()
+            })
+            .collect()
+    }
+
+    fn tmux(layout: TmuxLayout, synchronize: bool, inside_tmux: bool) -> Tmux {
+        Tmux {
+            layout,
+            synchronize,
+            inside_tmux,
+        }
+    }
+
+    #[test]
+    fn windows_start_a_session_outside_tmux() {
+        assert_eq!(
+            tmux(TmuxLayout::Windows, false, false)
+                .command_line(&command_generators(&["web-1", "web 2"]))
+                .unwrap(),
+            "tmux new-session -n web-1 -- ssh -t -- ec2-user@10.0.0.1 \
+             ';' new-window -n 'web 2' -- ssh -t -- ec2-user@10.0.0.2"
+        );
+    }
+
+    #[test]
+    fn windows_are_added_to_the_current_session_inside_tmux() {
+        let args = tmux(TmuxLayout::Windows, false, true)
+            .args(&command_generators(&["web-1"]))
+            .unwrap();
+
+        assert_eq!(args[..5], ["tmux", "new-window", "-n", "web-1", "--"]);
+    }
+
+    #[test]
+    fn panes_are_tiled_titled_and_synchronized() {
+        assert_eq!(
+            tmux(TmuxLayout::Panes, true, true)
+                .command_line(&command_generators(&["web-1", "web-2"]))
+                .unwrap(),
+            "tmux new-window -n blssh -- ssh -t -- ec2-user@10.0.0.1 \
+             ';' select-layout tiled ';' select-pane -T web-1 \
+             ';' split-window -- ssh -t -- ec2-user@10.0.0.2 \
+             ';' select-layout tiled ';' select-pane -T web-2 \
+             ';' set-window-option synchronize-panes on"
+        );
+    }
+
+    #[test]
+    fn printed_lines_push_instance_connect_keys_first() {
+        let opts = ConnectOptions {
+            instance_connect: true,
+            ..Default::default()
+        };
+        let config = Config {
+            default_user: Some(String::from("ec2-user")),
+            private_key: Some(std::path::PathBuf::from("/keys/prod.pem")),
+            ..Default::default()
+        };
+        let command_generators = ["i-1", "i-2"].map(|id| {
+            let instance = InstanceDetails {
+                instance_id: Some(id.to_string()),
+                private_ip: Some(String::from("10.0.0.1")),
+                ..Default::default()
+            };
+            CommandGenerator::new(&opts, config.clone(), instance).unwrap()
+        });
+
+        let command_line = tmux(TmuxLayout::Windows, false, false)
+            .command_line(&command_generators)
+            .unwrap();
+        let lines = command_line.split(" && ").collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert!(
+            lines[0].starts_with("aws ec2-instance-connect send-ssh-public-key --instance-id i-1")
+        );
+        assert!(
+            lines[1].starts_with("aws ec2-instance-connect send-ssh-public-key --instance-id i-2")
+        );
+        assert!(lines[2].starts_with("tmux new-session"));
+    }
+
+    #[test]
+    fn hostile_names_and_arguments_are_escaped() {
+        let mut command_generators = command_generators(&["#(touch /tmp/x)", "x;"]);
+        command_generators[1] = CommandGenerator::new(
+            &ConnectOptions {
+                tty: true,
+                command: vec![String::from("uptime;"), String::from(";")],
+                ..Default::default()
+            },
+            Config {
+                default_user: Some(String::from("ec2-user")),
+                ..Default::default()
+            },
+            command_generators[1].instance().clone(),
+        )
+        .unwrap();
+
+        let args = tmux(TmuxLayout::Panes, false, false)
+            .args(&command_generators)
+            .unwrap();
+        assert!(args.contains(&String::from("##(touch /tmp/x)")));
+        assert!(args.contains(&String::from("x\\;")));
+        assert!(args.contains(&String::from("uptime\\;")));
+        assert!(args.contains(&String::from("\\;")));
+        assert!(!args.iter().any(|arg| arg.starts_with("#(")));
+        assert!(!args
+            .iter()
+            .any(|arg| arg.ends_with(';') && arg != ";" && !arg.ends_with("\\;")));
+        assert_eq!(args.iter().filter(|arg| *arg == ";").count(), 5);
+    }
+
+    #[test]
+    fn assumed_role_credentials_are_set_on_every_window() {
+        let config = Config {
+            transport: Some(crate::cmdgen::Transport::Ssm),
+            accounts: vec![crate::config::Account {
+                name: String::from("production"),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let instance = InstanceDetails {
+            instance_id: Some(String::from("i-0abc")),
+            profile: Some(String::from("production")),
+            ..Default::default()
+        };
+        let mut command_generator =
+            CommandGenerator::new(&ConnectOptions::default(), config, instance).unwrap();
+        command_generator.set_role_credentials(Ok(crate::credentials::RoleCredentials {
+            role_arn: String::from("arn:aws:iam::111111111111:role/ops"),
+            access_key_id: String::from("ASIAKEY"),
+            secret_access_key: String::from("secret;"),
+            session_token: String::from("token"),
+            expiration: 0,
+        }));
+
+        let args = tmux(TmuxLayout::Windows, false, false)
+            .args(&[command_generator])
+            .unwrap();
+        assert_eq!(
+            args[..10],
+            [
+                "tmux",
+                "new-session",
+                "-n",
+                "i-0abc",
+                "-e",
+                "AWS_ACCESS_KEY_ID=ASIAKEY",
+                "-e",
+                "AWS_SECRET_ACCESS_KEY=secret\\;",
+                "-e",
+                "AWS_SESSION_TOKEN=token",
+            ]
+        );
+    }
+}
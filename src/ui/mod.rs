@@ -1,10 +1,12 @@
+mod search_input;
 mod stateful_list;
 
 use anyhow::{anyhow, Result};
+use search_input::SearchInput;
 use stateful_list::StatefulList;
 
 use crossterm::{
-    event::{self, Event, KeyCode, KeyEventKind},
+    event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
@@ -17,11 +19,16 @@ use ratatui::{
     Frame,
 };
 
-use std::{io::stdout, time::Duration};
+use std::{
+    io::stdout,
+    sync::mpsc::{Receiver, TryRecvError},
+    time::Duration,
+};
 
-use crate::instance_details::{InstanceDetails, InstanceSet};
+use crate::instance_details::{InstanceDetails, InstanceSet, DISPLAY_NAME_LENGTH};
+use crate::search::{MatchMode, Query};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 enum BlazeUiEvent {
     ListBottom,
     ListNext,
@@ -29,34 +36,193 @@ enum BlazeUiEvent {
     ListTop,
     Noop,
     Quit,
+    SearchBackspace,
+    SearchCancel,
+    SearchClearLine,
+    SearchInput(char),
+    SearchStart,
     Selected,
+    ToggleAll,
+    ToggleMark,
 }
 
-#[derive(Debug, Clone)]
+/* display name, instance & the char positions in the display name matched by the query */
+type ListElement = (String, InstanceDetails, Vec<usize>);
+
+#[derive(Debug)]
 pub struct Ui {
-    list: StatefulList<(String, InstanceDetails)>,
+    list: StatefulList<ListElement>,
     config: String,
+    /* instances matching the command line search, narrowed down further by the search input */
+    instance_set: InstanceSet,
+    query: Query,
+    match_mode: MatchMode,
+    search: SearchInput,
+    /* delivers a freshly fetched instance set while stale data is shown */
+    refresh: Option<Receiver<InstanceSet>>,
+    /* instances marked for a batch, in the order they were marked. marks survive re-filtering */
+    marked: Vec<InstanceDetails>,
 }
 
 impl Ui {
-    pub fn new(instance_set: InstanceSet, config: String) -> Result<Self> {
-        let list_elements = instance_set
+    pub fn new(
+        instance_set: InstanceSet,
+        query: Query,
+        match_mode: MatchMode,
+        config: String,
+    ) -> Result<Self> {
+        let list = StatefulList::with_items(Self::list_elements(&instance_set, &query));
+
+        Ok(Self {
+            list,
+            config,
+            instance_set,
+            query,
+            match_mode,
+            search: SearchInput::default(),
+            refresh: None,
+            marked: Vec::new(),
+        })
+    }
+
+    pub fn with_refresh(mut self, refresh: Receiver<InstanceSet>) -> Self {
+        self.refresh = Some(refresh);
+        self
+    }
+
+    /// whether a background refresh is still running
+    pub fn is_refreshing(&self) -> bool {
+        self.refresh.is_some()
+    }
+
+    /* swaps in a refreshed instance set, keeping the selected instance selected */
+    fn poll_refresh(&mut self) -> Result<()> {
+        let refreshed = match self.refresh.as_ref().map(|refresh| refresh.try_recv()) {
+            Some(Ok(instance_set)) => instance_set,
+            Some(Err(TryRecvError::Disconnected)) => {
+                self.refresh = None;
+                return Ok(());
+            }
+            Some(Err(TryRecvError::Empty)) | None => return Ok(()),
+        };
+
+        self.refresh = None;
+        let selected_id = self
+            .list
+            .state
+            .selected()
+            .and_then(|index| self.list.items.get(index))
+            .and_then(|(_, instance, _)| instance.instance_id.clone());
+
+        self.instance_set = refreshed.filter(&self.query)?;
+        self.refilter()?;
+
+        if let Some(index) = self.list.items.iter().position(|(_, instance, _)| {
+            instance.instance_id.is_some() && instance.instance_id == selected_id
+        }) {
+            self.list.state.select(Some(index));
+        }
+
+        Ok(())
+    }
+
+    fn is_marked(marked: &[InstanceDetails], instance: &InstanceDetails) -> bool {
+        marked
+            .iter()
+            .any(|marked| Self::same_instance(marked, instance))
+    }
+
+    fn same_instance(a: &InstanceDetails, b: &InstanceDetails) -> bool {
+        match (&a.instance_id, &b.instance_id) {
+            (Some(a), Some(b)) => a == b,
+            _ => a == b,
+        }
+    }
+
+    /* marks or unmarks the highlighted instance */
+    fn toggle_mark(&mut self) {
+        let Some((_, instance, _)) = self
+            .list
+            .state
+            .selected()
+            .and_then(|index| self.list.items.get(index))
+        else {
+            return;
+        };
+
+        match Self::is_marked(&self.marked, instance) {
+            true => self
+                .marked
+                .retain(|marked| !Self::same_instance(marked, instance)),
+            false => self.marked.push(instance.clone()),
+        }
+    }
+
+    /* marks every visible instance, or unmarks them all if they already are */
+    fn toggle_all(&mut self) {
+        let visible = self
+            .list
+            .items
+            .iter()
+            .map(|(_, instance, _)| instance.clone())
+            .collect::<Vec<_>>();
+
+        match visible
+            .iter()
+            .all(|instance| Self::is_marked(&self.marked, instance))
+        {
+            true => self
+                .marked
+                .retain(|marked| !Self::is_marked(&visible, marked)),
+            false => visible.into_iter().for_each(|instance| {
+                if !Self::is_marked(&self.marked, &instance) {
+                    self.marked.push(instance);
+                }
+            }),
+        }
+    }
+
+    fn list_elements(instance_set: &InstanceSet, query: &Query) -> Vec<ListElement> {
+        instance_set
             .instances
             .iter()
             .map(|instance| {
                 (
                     instance.display_name().unwrap_or_default(),
                     instance.clone(),
+                    Self::highlights(instance, query),
                 )
             })
-            .collect::<Vec<_>>();
+            .collect()
+    }
 
-        let list = StatefulList::with_items(list_elements);
+    /* re-filters the list with the search input. a malformed query keeps the current list &
+     * shows the parse error instead */
+    fn refilter(&mut self) -> Result<()> {
+        if self.search.is_empty() {
+            self.search.error = None;
+            self.list
+                .set_items(Self::list_elements(&self.instance_set, &self.query));
+            return Ok(());
+        }
+
+        match Query::parse_with(&self.search.query, self.match_mode) {
+            Ok(query) => {
+                self.search.error = None;
+                let filtered_instance_set = self.instance_set.filter(&query)?;
+                self.list
+                    .set_items(Self::list_elements(&filtered_instance_set, &query));
+            }
+            Err(e) => {
+                self.search.error = e.to_string().lines().next().map(String::from);
+            }
+        }
 
-        Ok(Self { list, config })
+        Ok(())
     }
 
-    pub fn run(&mut self) -> Result<InstanceDetails> {
+    /// the marked instances, or the highlighted one when none are marked
+    pub fn run(&mut self) -> Result<Vec<InstanceDetails>> {
         /* terminal setup */
         enable_raw_mode()?;
         let mut stdout = stdout();
@@ -67,8 +233,18 @@ impl Ui {
         /* create app and run it */
         loop {
             /* Render UI */
+            self.poll_refresh()?;
+            let status = self.status();
             terminal.draw(|frame| {
-                Self::ui(frame, &mut self.list, self.config.clone()).unwrap();
+                Self::ui(
+                    frame,
+                    &mut self.list,
+                    &self.search,
+                    &status,
+                    &self.marked,
+                    self.config.clone(),
+                )
+                .unwrap();
             })?;
 
             /* Handle Events */
@@ -77,7 +253,8 @@ impl Ui {
                     self.list.state.select(None);
                     disable_raw_mode()?;
                     execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
-                    break;
+                    /* quitting drops the marks too */
+                    return Err(anyhow!("No instance selected"));
                 }
                 BlazeUiEvent::Noop => {
                     continue;
@@ -94,6 +271,31 @@ impl Ui {
                 BlazeUiEvent::ListPrevious => {
                     self.list.previous();
                 }
+                BlazeUiEvent::SearchStart => {
+                    self.search.start();
+                }
+                BlazeUiEvent::SearchInput(c) => {
+                    self.search.push(c);
+                    self.refilter()?;
+                }
+                BlazeUiEvent::SearchBackspace => {
+                    self.search.pop();
+                    self.refilter()?;
+                }
+                BlazeUiEvent::SearchClearLine => {
+                    self.search.clear_line();
+                    self.refilter()?;
+                }
+                BlazeUiEvent::SearchCancel => {
+                    self.search.cancel();
+                    self.refilter()?;
+                }
+                BlazeUiEvent::ToggleMark => {
+                    self.toggle_mark();
+                }
+                BlazeUiEvent::ToggleAll => {
+                    self.toggle_all();
+                }
                 BlazeUiEvent::Selected => {
                     disable_raw_mode()?;
                     execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
@@ -102,15 +304,68 @@ impl Ui {
             }
         }
 
-        match self.list.state.selected() {
-            Some(index) => Ok(self.list.items[index].1.clone()),
-            None => Err(anyhow!("No instance selected")),
+        self.selection()
+    }
+
+    /* marks win over the highlighted row, even when the search hides every marked instance */
+    fn selection(&self) -> Result<Vec<InstanceDetails>> {
+        if !self.marked.is_empty() {
+            return Ok(self.marked.clone());
+        }
+
+        self.list
+            .state
+            .selected()
+            .and_then(|index| self.list.items.get(index))
+            .map(|(_, instance, _)| vec![instance.clone()])
+            .ok_or(anyhow!("No instance selected"))
+    }
+
+    /* match count & the age of the shown data, e.g. "12/340 - fetched 5m ago (refreshing)" */
+    fn status(&self) -> String {
+        let mut status = format!(
+            "{}/{}",
+            self.list.items.len(),
+            self.instance_set.instances.len()
+        );
+        if let Some(age_label) = self.instance_set.age_label() {
+            status = format!("{} - {}", status, age_label);
+        }
+        if self.is_refreshing() {
+            status = format!("{} (refreshing)", status);
+        }
+        if !self.marked.is_empty() {
+            status = format!("{} - {} selected", status, self.marked.len());
         }
+
+        status
+    }
+
+    /* only the part of the name shown in the display name can be highlighted */
+    fn highlights(instance: &InstanceDetails, query: &Query) -> Vec<usize> {
+        let name_length = instance
+            .instance_name
+            .as_deref()
+            .map(|name| name.chars().count())
+            .unwrap_or_default();
+        let visible = match name_length > DISPLAY_NAME_LENGTH {
+            true => DISPLAY_NAME_LENGTH - 3,
+            false => name_length,
+        };
+
+        query
+            .highlights(instance)
+            .into_iter()
+            .filter(|position| *position < visible)
+            .collect()
     }
 
     fn ui(
         frame: &mut Frame,
-        list: &mut StatefulList<(String, InstanceDetails)>,
+        list: &mut StatefulList<ListElement>,
+        search: &SearchInput,
+        status: &str,
+        marked: &[InstanceDetails],
         config: String,
     ) -> Result<()> {
         let slices = Layout::default()
@@ -118,20 +373,43 @@ impl Ui {
             .constraints([Constraint::Percentage(70), Constraint::Percentage(30)])
             .split(frame.size());
 
+        let instances_slices = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(3), Constraint::Length(3)])
+            .split(slices[0]);
+
         let dashboard_slices = Layout::default()
             .direction(Direction::Vertical)
-            .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
+            .constraints([
+                Constraint::Percentage(30),
+                Constraint::Percentage(40),
+                Constraint::Percentage(30),
+            ])
             .split(slices[1]);
 
         /* render keybindings */
-        let keybindings_list_items = vec![
-            ListItem::new("j/Down: Next item"),
-            ListItem::new("k/Up: Previous item"),
-            ListItem::new("g: Top item"),
-            ListItem::new("G: Bottom item"),
-            ListItem::new("Enter: Select item"),
-            ListItem::new("q/Esc: Quit"),
-        ];
+        let keybindings_list_items = match search.editing {
+            true => vec![
+                ListItem::new("Down: Next item"),
+                ListItem::new("Up: Previous item"),
+                ListItem::new("Backspace: Delete character"),
+                ListItem::new("Ctrl-U: Clear query"),
+                ListItem::new("Enter: Select item"),
+                ListItem::new("Esc: Cancel search"),
+            ],
+            false => vec![
+                ListItem::new("j/Down: Next item"),
+                ListItem::new("k/Up: Previous item"),
+                ListItem::new("g: Top item"),
+                ListItem::new("G: Bottom item"),
+                ListItem::new("/: Search"),
+                ListItem::new("Space: Mark item"),
+                ListItem::new("a: Mark all shown items"),
+                ListItem::new("Enter: Select item(s)"),
+                ListItem::new("Esc: Clear search / Quit"),
+                ListItem::new("q: Quit"),
+            ],
+        };
 
         let keybindings_list = List::new(keybindings_list_items).block(
             Block::default()
@@ -144,6 +422,31 @@ impl Ui {
 
         frame.render_widget(keybindings_list, dashboard_slices[0]);
 
+        /* render selected instance details */
+        let details = list
+            .state
+            .selected()
+            .and_then(|index| list.items.get(index))
+            .map(|(_, instance, _)| {
+                instance
+                    .details()
+                    .into_iter()
+                    .map(|(label, value)| format!("{}: {}", label, value))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            })
+            .unwrap_or_default();
+
+        let details = Paragraph::new(details).block(
+            Block::default()
+                .borders(Borders::ALL)
+                .bg(Color::Black)
+                .fg(Color::White)
+                .title("Details")
+                .padding(Padding::new(4, 1, 1, 1)),
+        );
+        frame.render_widget(details, dashboard_slices[1]);
+
         /* render raw config */
         let config = Paragraph::new(config).block(
             Block::default()
@@ -153,14 +456,37 @@ impl Ui {
                 .title("Config")
                 .padding(Padding::new(4, 1, 1, 1)),
         );
-        frame.render_widget(config, dashboard_slices[1]);
+        frame.render_widget(config, dashboard_slices[2]);
 
         /* render instances list */
         let prepared_items: Vec<ListItem> = list
             .items
             .iter()
-            .map(|(dsp_name, _item)| {
-                ListItem::new(dsp_name.clone()).style(
+            .map(|(dsp_name, item, highlights)| {
+                let marker = match Self::is_marked(marked, item) {
+                    true => Span::styled(
+                        "* ",
+                        Style::default()
+                            .fg(ratatui::style::Color::Cyan)
+                            .add_modifier(Modifier::BOLD),
+                    ),
+                    false => Span::raw("  "),
+                };
+                let spans = std::iter::once(marker)
+                    .chain(dsp_name.chars().enumerate().map(|(index, c)| {
+                        match highlights.contains(&index) {
+                            true => Span::styled(
+                                c.to_string(),
+                                Style::default()
+                                    .fg(ratatui::style::Color::Yellow)
+                                    .add_modifier(Modifier::BOLD),
+                            ),
+                            false => Span::raw(c.to_string()),
+                        }
+                    }))
+                    .collect::<Vec<_>>();
+
+                ListItem::new(Line::from(spans)).style(
                     Style::default()
                         .fg(ratatui::style::Color::White)
                         .bg(ratatui::style::Color::Black),
@@ -174,7 +500,7 @@ impl Ui {
                     .borders(Borders::ALL)
                     .bg(Color::Black)
                     .padding(Padding::new(4, 4, 1, 1))
-                    .title("Instances"),
+                    .title(format!("Instances ({})", status)),
             )
             .highlight_style(
                 Style::default()
@@ -184,7 +510,33 @@ impl Ui {
             )
             .highlight_symbol(">>= ");
 
-        frame.render_stateful_widget(prepared_list, slices[0], &mut list.state);
+        frame.render_stateful_widget(prepared_list, instances_slices[0], &mut list.state);
+
+        /* render search input */
+        let search_title = match &search.error {
+            Some(error) => format!("Search - {}", error),
+            None => String::from("Search"),
+        };
+        let search_style = match (search.editing, search.error.is_some()) {
+            (_, true) => Style::default().fg(Color::Red),
+            (true, false) => Style::default().fg(Color::Yellow),
+            (false, false) => Style::default().fg(Color::White),
+        };
+        let search_input = Paragraph::new(format!("/{}", search.query)).block(
+            Block::default()
+                .borders(Borders::ALL)
+                .bg(Color::Black)
+                .style(search_style)
+                .title(search_title),
+        );
+        frame.render_widget(search_input, instances_slices[1]);
+
+        if search.editing {
+            frame.set_cursor(
+                instances_slices[1].x + 2 + search.query.chars().count() as u16,
+                instances_slices[1].y + 1,
+            );
+        }
 
         Ok(())
     }
@@ -192,21 +544,184 @@ impl Ui {
     fn read_events(&self) -> Result<BlazeUiEvent> {
         match event::poll(Duration::from_millis(250))? {
             true => match event::read()? {
-                Event::Key(event) => match event.kind {
-                    KeyEventKind::Press => match event.code {
-                        KeyCode::Char('q') | KeyCode::Esc => Ok(BlazeUiEvent::Quit),
-                        KeyCode::Char('j') | KeyCode::Down => Ok(BlazeUiEvent::ListNext),
-                        KeyCode::Char('G') => Ok(BlazeUiEvent::ListBottom),
-                        KeyCode::Char('g') => Ok(BlazeUiEvent::ListTop),
-                        KeyCode::Char('k') | KeyCode::Up => Ok(BlazeUiEvent::ListPrevious),
-                        KeyCode::Enter => Ok(BlazeUiEvent::Selected),
-                        _ => Ok(BlazeUiEvent::Noop),
-                    },
-                    _ => Ok(BlazeUiEvent::Noop),
-                },
+                Event::Key(event) => Ok(self.key_event(event)),
                 _ => Ok(BlazeUiEvent::Noop),
             },
             false => Ok(BlazeUiEvent::Noop),
         }
     }
+
+    fn key_event(&self, event: KeyEvent) -> BlazeUiEvent {
+        match event.kind {
+            KeyEventKind::Press if self.search.editing => match event.code {
+                KeyCode::Esc => BlazeUiEvent::SearchCancel,
+                KeyCode::Enter => BlazeUiEvent::Selected,
+                KeyCode::Down => BlazeUiEvent::ListNext,
+                KeyCode::Up => BlazeUiEvent::ListPrevious,
+                KeyCode::Backspace => BlazeUiEvent::SearchBackspace,
+                KeyCode::Char('u') if event.modifiers.contains(KeyModifiers::CONTROL) => {
+                    BlazeUiEvent::SearchClearLine
+                }
+                KeyCode::Char(c) => BlazeUiEvent::SearchInput(c),
+                _ => BlazeUiEvent::Noop,
+            },
+            KeyEventKind::Press => match event.code {
+                KeyCode::Esc if !self.search.is_empty() => BlazeUiEvent::SearchCancel,
+                KeyCode::Char('q') | KeyCode::Esc => BlazeUiEvent::Quit,
+                KeyCode::Char('/') => BlazeUiEvent::SearchStart,
+                KeyCode::Char('j') | KeyCode::Down => BlazeUiEvent::ListNext,
+                KeyCode::Char('G') => BlazeUiEvent::ListBottom,
+                KeyCode::Char('g') => BlazeUiEvent::ListTop,
+                KeyCode::Char('k') | KeyCode::Up => BlazeUiEvent::ListPrevious,
+                KeyCode::Char(' ') => BlazeUiEvent::ToggleMark,
+                KeyCode::Char('a') => BlazeUiEvent::ToggleAll,
+                KeyCode::Enter => BlazeUiEvent::Selected,
+                _ => BlazeUiEvent::Noop,
+            },
+            _ => BlazeUiEvent::Noop,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ui(names: &[&str]) -> Ui {
+        let instances = names
+            .iter()
+            .enumerate()
+            .map(|(index, name)| InstanceDetails {
+                instance_id: Some(format!("i-{}", index)),
+                instance_name: Some(name.to_string()),
+                ..Default::default()
+            })
+            .collect();
+        let instance_set = InstanceSet {
+            instances,
+            ..Default::default()
+        };
+
+        Ui::new(
+            instance_set,
+            Query::parse_with("", MatchMode::default()).unwrap(),
+            MatchMode::default(),
+            String::new(),
+        )
+        .unwrap()
+    }
+
+    fn marked_names(ui: &Ui) -> Vec<String> {
+        ui.marked
+            .iter()
+            .filter_map(|instance| instance.instance_name.clone())
+            .collect()
+    }
+
+    #[test]
+    fn marks_survive_refiltering() {
+        let mut ui = ui(&["web-1", "web-2", "db-1"]);
+        ui.toggle_mark();
+
+        ui.search.query = String::from("db");
+        ui.refilter().unwrap();
+        ui.toggle_mark();
+
+        ui.search.cancel();
+        ui.refilter().unwrap();
+        assert_eq!(marked_names(&ui), vec!["web-1", "db-1"]);
+
+        ui.list.first();
+        ui.toggle_mark();
+        assert_eq!(marked_names(&ui), vec!["db-1"]);
+    }
+
+    #[test]
+    fn toggle_all_marks_visible_rows_then_unmarks_them() {
+        let mut ui = ui(&["web-1", "web-2", "db-1"]);
+        ui.search.query = String::from("web");
+        ui.refilter().unwrap();
+
+        ui.toggle_all();
+        assert_eq!(marked_names(&ui), vec!["web-1", "web-2"]);
+
+        ui.toggle_all();
+        assert!(ui.marked.is_empty());
+    }
+
+    #[test]
+    fn editing_keys_edit_the_search() {
+        let mut ui = ui(&["web-1", "web-2", "db-1"]);
+        let key = |ui: &Ui, code: KeyCode, modifiers: KeyModifiers| {
+            ui.key_event(KeyEvent::new(code, modifiers))
+        };
+        let apply = |ui: &mut Ui, event: BlazeUiEvent| {
+            match event {
+                BlazeUiEvent::SearchStart => ui.search.start(),
+                BlazeUiEvent::SearchInput(c) => ui.search.push(c),
+                BlazeUiEvent::SearchBackspace => ui.search.pop(),
+                BlazeUiEvent::SearchClearLine => ui.search.clear_line(),
+                BlazeUiEvent::SearchCancel => ui.search.cancel(),
+                event => panic!("unexpected {:?}", event),
+            }
+            ui.refilter().unwrap();
+        };
+
+        let event = key(&ui, KeyCode::Char('/'), KeyModifiers::NONE);
+        apply(&mut ui, event);
+        for c in "dbx".chars() {
+            let event = key(&ui, KeyCode::Char(c), KeyModifiers::NONE);
+            assert_eq!(event, BlazeUiEvent::SearchInput(c));
+            apply(&mut ui, event);
+        }
+        assert!(ui.list.items.is_empty());
+
+        let event = key(&ui, KeyCode::Backspace, KeyModifiers::NONE);
+        assert_eq!(event, BlazeUiEvent::SearchBackspace);
+        apply(&mut ui, event);
+        assert_eq!(ui.search.query, "db");
+        assert_eq!(ui.list.items.len(), 1);
+
+        let event = key(&ui, KeyCode::Char('u'), KeyModifiers::CONTROL);
+        assert_eq!(event, BlazeUiEvent::SearchClearLine);
+        apply(&mut ui, event);
+        assert_eq!(ui.search.query, "");
+        assert!(ui.search.editing);
+        assert_eq!(ui.list.items.len(), 3);
+
+        for c in "web".chars() {
+            apply(&mut ui, BlazeUiEvent::SearchInput(c));
+        }
+        let event = key(&ui, KeyCode::Esc, KeyModifiers::NONE);
+        assert_eq!(event, BlazeUiEvent::SearchCancel);
+        apply(&mut ui, event);
+        assert_eq!(ui.search.query, "");
+        assert!(!ui.search.editing);
+        assert_eq!(ui.list.items.len(), 3);
+        assert_eq!(
+            key(&ui, KeyCode::Esc, KeyModifiers::NONE),
+            BlazeUiEvent::Quit
+        );
+    }
+
+    #[test]
+    fn marks_are_selected_when_the_search_hides_every_row() {
+        let mut ui = ui(&["web-1", "web-2", "db-1"]);
+        ui.toggle_all();
+        ui.search.query = String::from("nothing-matches");
+        ui.refilter().unwrap();
+        assert!(ui.list.items.is_empty());
+
+        let selected = ui.selection().unwrap();
+        assert_eq!(selected.len(), 3);
+
+        ui.marked.clear();
+        assert!(ui.selection().is_err());
+        ui.search.cancel();
+        ui.refilter().unwrap();
+        assert_eq!(
+            ui.selection().unwrap()[0].instance_name.as_deref(),
+            Some("web-1")
+        );
+    }
 }
@@ -0,0 +1,72 @@
+#[derive(Debug, Clone, Default)]
+pub struct SearchInput {
+    pub query: String,
+    pub editing: bool,
+    pub error: Option<String>,
+}
+
+impl SearchInput {
+    pub fn start(&mut self) {
+        self.editing = true;
+    }
+
+    pub fn push(&mut self, c: char) {
+        self.query.push(c);
+    }
+
+    pub fn pop(&mut self) {
+        self.query.pop();
+    }
+
+    pub fn clear_line(&mut self) {
+        self.query.clear();
+    }
+
+    /* drops the query altogether & leaves search mode */
+    pub fn cancel(&mut self) {
+        self.query.clear();
+        self.error = None;
+        self.editing = false;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.query.trim().is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edits_the_query() {
+        let mut search = SearchInput::default();
+        search.start();
+        "web".chars().for_each(|c| search.push(c));
+        search.pop();
+        assert_eq!(search.query, "we");
+
+        search.clear_line();
+        assert!(search.is_empty());
+        assert!(search.editing);
+
+        search.pop();
+        assert_eq!(search.query, "");
+    }
+
+    #[test]
+    fn cancel_drops_the_query_and_error() {
+        let mut search = SearchInput {
+            query: String::from("  "),
+            editing: true,
+            error: Some(String::from("unbalanced parenthesis")),
+        };
+        assert!(search.is_empty());
+
+        search.push('x');
+        search.cancel();
+        assert_eq!(search.query, "");
+        assert_eq!(search.error, None);
+        assert!(!search.editing);
+    }
+}
@@ -8,12 +8,21 @@ pub struct StatefulList<T> {
 
 impl<T> StatefulList<T> {
     pub fn with_items(items: Vec<T>) -> Self {
-        let mut list_state = ListState::default();
-        list_state.select(Some(0));
+        let mut list = Self {
+            state: ListState::default(),
+            items: Vec::new(),
+        };
+        list.set_items(items);
+
+        list
+    }
 
-        Self {
-            state: list_state,
-            items,
+    /* replaces the items, selecting the first one if there is any */
+    pub fn set_items(&mut self, items: Vec<T>) {
+        self.items = items;
+        self.state = ListState::default();
+        if !self.items.is_empty() {
+            self.state.select(Some(0));
         }
     }
 
@@ -43,6 +52,10 @@ impl<T> StatefulList<T> {
     } */
 
     pub fn next(&mut self) {
+        if self.items.is_empty() {
+            return;
+        }
+
         let next_index = match self.state.selected() {
             Some(i) => match i {
                 i if i >= self.items.len() - 1 => 0,
@@ -55,14 +68,26 @@ impl<T> StatefulList<T> {
     }
 
     pub fn last(&mut self) {
+        if self.items.is_empty() {
+            return;
+        }
+
         self.state.select(Some(self.items.len() - 1));
     }
 
     pub fn first(&mut self) {
+        if self.items.is_empty() {
+            return;
+        }
+
         self.state.select(Some(0));
     }
 
     pub fn previous(&mut self) {
+        if self.items.is_empty() {
+            return;
+        }
+
         let prev_index = match self.state.selected() {
             Some(i) => match i {
                 0 => self.items.len() - 1,
@@ -73,3 +98,40 @@ impl<T> StatefulList<T> {
         self.state.select(Some(prev_index));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_lists_select_nothing() {
+        let mut list = StatefulList::<u8>::with_items(Vec::new());
+        assert_eq!(list.state.selected(), None);
+
+        list.next();
+        list.previous();
+        list.first();
+        list.last();
+        assert_eq!(list.state.selected(), None);
+
+        list.set_items(vec![1, 2, 3]);
+        list.last();
+        assert_eq!(list.state.selected(), Some(2));
+
+        list.set_items(Vec::new());
+        assert_eq!(list.state.selected(), None);
+        list.next();
+        assert_eq!(list.state.selected(), None);
+    }
+
+    #[test]
+    fn navigation_wraps_around() {
+        let mut list = StatefulList::with_items(vec![1, 2, 3]);
+        assert_eq!(list.state.selected(), Some(0));
+
+        list.previous();
+        assert_eq!(list.state.selected(), Some(2));
+        list.next();
+        assert_eq!(list.state.selected(), Some(0));
+    }
+}